@@ -0,0 +1,150 @@
+use super::config::Proxy;
+use super::level;
+use super::template::Captures;
+use crate::http::prelude::startline::StartLine;
+use crate::http::prelude::{Files, Response};
+
+/// What to do with a request once a route is taken
+#[derive(Debug)]
+pub enum Action {
+    Proxy(Box<Proxy>),
+    Redirect(Redirect),
+    Return(Response),
+    Files(Files),
+}
+
+impl TryFrom<&level::Level> for Action {
+    type Error = level::Error;
+
+    fn try_from(level: &level::Level) -> Result<Self, Self::Error> {
+        if let Ok(x) = level.level(vec!["redirect"]) {
+            Ok(Action::Redirect(x.try_into()?))
+        } else if let Ok(x) = level.level(vec!["return"]) {
+            Ok(Action::Return(response(x)?))
+        } else if level.value(vec!["root"]).is_ok() {
+            Ok(Action::Files(files(level)?))
+        } else {
+            Ok(Action::Proxy(Box::new(level.try_into()?)))
+        }
+    }
+}
+
+/// Redirect answered by the proxy
+///
+/// `$path` and `$query`(with leading `?`) are substituted in `to`,
+/// along with captures of the route.
+#[derive(Debug)]
+pub struct Redirect {
+    to: String,
+    code: u16,
+}
+
+impl Redirect {
+    /// Permanent redirect to the https URL of the same host
+    pub fn https() -> Self {
+        Redirect {
+            to: "https://$host$path$query".to_string(),
+            code: 308,
+        }
+    }
+    pub fn response(&self, startline: &StartLine, captures: &Captures) -> Response {
+        let (_, origin) = startline.split_target();
+        let split = origin
+            .iter()
+            .position(|&x| x == b'?')
+            .unwrap_or(origin.len());
+        let (path, query) = origin.split_at(split);
+
+        let mut captures = captures.clone();
+        captures.set("path", String::from_utf8_lossy(path).into_owned());
+        captures.set("query", String::from_utf8_lossy(query).into_owned());
+
+        Response::new(self.code).header("Location", &captures.expand(&self.to))
+    }
+}
+
+impl TryFrom<&level::Level> for Redirect {
+    type Error = level::Error;
+
+    fn try_from(level: &level::Level) -> Result<Self, Self::Error> {
+        let to = level.value(vec!["to"])?.try_into()?;
+        let code = match level.value(vec!["code"]) {
+            Ok(x) => {
+                let code: i64 = x.try_into()?;
+                code
+            }
+            Err(_) => 302,
+        };
+        match code {
+            301 | 302 | 307 | 308 => Ok(Redirect {
+                to,
+                code: code as u16,
+            }),
+            _ => Err(level::Error::MisMatchType),
+        }
+    }
+}
+
+/// Parse the static response of `return`, `\n` in body is a line break
+fn response(level: &level::Level) -> Result<Response, level::Error> {
+    let status: i64 = match level.value(vec!["status"]) {
+        Ok(x) => x.try_into()?,
+        Err(_) => 200,
+    };
+    let status = u16::try_from(status).map_err(|_| level::Error::MisMatchType)?;
+    let mut response = Response::new(status);
+
+    if let Ok(x) = level.value(vec!["body"]) {
+        let body: String = x.try_into()?;
+        response = response.body(body.replace("\\n", "\n").into_bytes());
+    }
+    for header in level.list(vec!["headers"]).unwrap_or_default() {
+        let header: String = header.try_into()?;
+        let (name, value) = header
+            .split_once(':')
+            .ok_or(level::Error::MisMatchStructure)?;
+        response = response.header(name.trim(), value.trim());
+    }
+    Ok(response)
+}
+
+/// Parse `root`, with optional `index`(default `index.html`) and `fallback`
+fn files(level: &level::Level) -> Result<Files, level::Error> {
+    let root: String = level.value(vec!["root"])?.try_into()?;
+    let index = match level.value(vec!["index"]) {
+        Ok(x) => x.try_into()?,
+        Err(_) => "index.html".to_string(),
+    };
+    let fallback = match level.value(vec!["fallback"]) {
+        Ok(x) => Some(x.try_into()?),
+        Err(_) => None,
+    };
+    Ok(Files {
+        root: root.into(),
+        index,
+        fallback,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn redirect() {
+        let redirect = Redirect {
+            to: "https://b.example.com$path$query".to_string(),
+            code: 308,
+        };
+        let startline: StartLine = b"GET /a/b?c=d HTTP/1.1".as_ref().try_into().unwrap();
+        let response = redirect.response(&startline, &Captures::new());
+        assert_eq!(response.status, 308);
+        assert_eq!(
+            response.headers,
+            vec![(
+                "Location".to_string(),
+                "https://b.example.com/a/b?c=d".to_string()
+            )]
+        );
+    }
+}
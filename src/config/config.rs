@@ -1,20 +1,41 @@
-use std::hash::{Hash, Hasher};
 use std::net::ToSocketAddrs;
+use std::{fs, io, path};
 use std::{
-    collections::{self, hash_map::DefaultHasher},
     net,
-    sync::atomic::{self, Ordering},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
-use std::{fs, io};
 
+use super::action::{Action, Redirect};
 use super::level::{self};
+use super::network::Network;
 use super::parser;
+use super::rewrite::Rewrite;
+use super::rule::Matcher;
+use super::table::HostTable;
+use super::template::Captures;
+use crate::http::prelude::buffer::Buffer;
+use crate::http::prelude::compress::{Compress, Compression};
+use crate::http::prelude::header::Fields;
+use crate::http::prelude::health::{Check, Health, Probe};
+use crate::http::prelude::keepalive::{KeepAlive, Pool};
+use crate::http::prelude::load::{self, Active, Load};
+use crate::http::prelude::outlier::{Eject, Outlier};
+use crate::http::prelude::ring::Ring;
+use crate::http::prelude::startline::StartLine;
+use crate::http::prelude::sticky::{Affinity, Sticky};
+use crate::http::prelude::{Cache, Disk};
 
 #[derive(Debug)]
 pub struct AppState {
-    routes: collections::BTreeMap<u64, Balancer>,
+    hosts: HostTable<Vec<Route>>,
     pub addr: String,
+    pub port: u16,
     pub thread: usize,
+    // address of the admin listener
+    pub admin: Option<String>,
+    // clients whose `X-Forwarded-Proto` is taken as is
+    trusted: Vec<Network>,
 }
 
 impl AppState {
@@ -38,87 +59,1313 @@ impl AppState {
             .try_into()
             .unwrap();
         let thread: usize = thread.try_into().unwrap();
+        let admin: Option<String> = root
+            .value(vec!["server", "admin"])
+            .ok()
+            .map(|x| x.try_into().unwrap());
+        let trusted: Vec<Network> = root
+            .list(vec!["server", "trusted-proxies"])
+            .unwrap_or_default()
+            .into_iter()
+            .map(|x| {
+                let network: String = x.try_into().unwrap();
+                network
+                    .parse()
+                    .unwrap_or_else(|_| panic!("fail parsing network {:?}", network))
+            })
+            .collect();
+        let port = addr
+            .to_socket_addrs()
+            .expect("fail parsing server address")
+            .next()
+            .unwrap()
+            .port();
 
-        let mut routes = collections::BTreeMap::new();
+        let mut table = HostTable::new();
         for host in hosts {
-            routes.insert(host.0, host.1);
+            table.insert(&host.0, host.1);
         }
 
         AppState {
-            routes,
+            hosts: table,
             addr,
+            port,
             thread,
+            admin,
+            trusted,
         }
     }
-    pub fn route(&self, domain: u64) -> Option<net::SocketAddr> {
-        match self.routes.get(&domain) {
-            Some(balancer) => Some(balancer.route()),
-            None => None,
+    /// Whether the client is a proxy in front telling the scheme used with `X-Forwarded-Proto`
+    pub fn trusted(&self, addr: net::IpAddr) -> bool {
+        self.trusted.iter().any(|x| x.contains(addr))
+    }
+    /// Lookup the first route of a normalized host matching the request
+    pub fn route(
+        &self,
+        domain: &str,
+        startline: &StartLine,
+        fields: &Fields,
+    ) -> Option<(&Route, Captures)> {
+        let (routes, mut captures) = self.hosts.get(domain)?;
+        let name = match domain.rfind(':') {
+            Some(x) if !domain.ends_with(']') => &domain[..x],
+            _ => domain,
+        };
+        captures.set("host", name.to_string());
+        routes.iter().find_map(|route| {
+            let mut captures = captures.clone();
+            if route.matcher.matches(startline, fields, &mut captures) {
+                Some((route, captures))
+            } else {
+                None
+            }
+        })
+    }
+    /// Health checks of every host
+    pub fn health_checks(&self) -> Vec<Arc<Health>> {
+        let mut checks: Vec<Arc<Health>> = vec![];
+        for route in self.hosts.values().flatten() {
+            let health = match &route.action {
+                Action::Proxy(x) => x.balancer.health.as_ref(),
+                _ => None,
+            };
+            if let Some(health) = health {
+                if !checks.iter().any(|x| Arc::ptr_eq(x, health)) {
+                    checks.push(health.clone());
+                }
+            }
         }
+        checks
     }
-    pub fn hash(&self, domain: &str) -> u64 {
-        hash(domain)
+    /// Caches of every host, each once
+    pub fn caches(&self) -> Vec<&Cache> {
+        let mut caches: Vec<&Arc<Cache>> = vec![];
+        for cache in self
+            .hosts
+            .values()
+            .flatten()
+            .filter_map(|x| x.cache.as_ref())
+        {
+            if !caches.iter().any(|x| Arc::ptr_eq(x, cache)) {
+                caches.push(cache);
+            }
+        }
+        caches.into_iter().map(|x| x.as_ref()).collect()
     }
     pub fn shorten(&mut self) {
         todo!()
     }
 }
 
+#[derive(Debug)]
+pub struct Route {
+    matcher: Matcher,
+    pub action: Action,
+    hsts: Option<String>,
+    cache: Option<Arc<Cache>>,
+    // clients allowed to purge the cache
+    purge: Vec<Network>,
+}
+
+impl Route {
+    /// Header fields added to every response of the route
+    pub fn response_headers(&self, secure: bool) -> Vec<(String, String)> {
+        match &self.hsts {
+            Some(x) if secure => vec![("Strict-Transport-Security".to_string(), x.clone())],
+            _ => vec![],
+        }
+    }
+    /// Response cache shared by routes of the host
+    pub fn cache(&self) -> Option<&Cache> {
+        self.cache.as_deref()
+    }
+    /// Whether the client may send `PURGE`
+    pub fn purge_allowed(&self, addr: net::IpAddr) -> bool {
+        self.purge.iter().any(|x| x.contains(addr))
+    }
+}
+
+/// Forward the request to one of the upstream
+#[derive(Debug)]
+pub struct Proxy {
+    balancer: Balancer,
+    rewrite: Rewrite,
+    headers: Vec<(String, String)>,
+    compress: Option<Compress>,
+    retry: Retry,
+    buffer_request: Option<Buffer>,
+    // set by host
+    buffer_response: Option<Buffer>,
+    pool: Option<Arc<Pool>>,
+}
+
+/// How requests failing upstream are tried again, each time on another upstream when there is one
+///
+/// A failed connection is always retried. Reset or a status in `statuses` after the
+/// request was sent is retried only for idempotent requests with the body at hand,
+/// or any request with `buffer-request`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Retry {
+    // tries after the first one
+    pub count: usize,
+    // for each try to connect and receive the response head, None to wait as long as it takes
+    pub timeout: Option<Duration>,
+    // before the first retry, doubled for each one after
+    pub backoff: Duration,
+    pub statuses: Vec<u16>,
+}
+
+impl Proxy {
+    /// Next upstream in turn, one not `tried` yet when there is any
+    ///
+    /// With hash balancing, requests with the same `key` go to the same upstream.
+    pub fn next_upstream(&self, tried: &[net::SocketAddr], key: Option<&[u8]>) -> net::SocketAddr {
+        self.balancer.next(tried, key)
+    }
+    /// Key of the request for hash balancing, None when it is balanced otherwise
+    /// or the request has no such key
+    pub fn hash_key(
+        &self,
+        startline: &StartLine,
+        fields: &Fields,
+        peer: Option<net::IpAddr>,
+    ) -> Option<Vec<u8>> {
+        let key = match &self.balancer.balance {
+            Balance::Hash(x, _) => x,
+            _ => return None,
+        };
+        match key {
+            HashKey::ClientIp => peer.map(|x| x.to_string().into_bytes()),
+            HashKey::Header(name) => fields.get(name).map(|x| x.to_vec()),
+            HashKey::Cookie(name) => fields.cookie(name).map(|x| x.to_vec()),
+            HashKey::Path => {
+                let (_, origin) = startline.split_target();
+                let path = origin.split(|&x| x == b'?').next().unwrap_or_default();
+                Some(path.to_vec())
+            }
+        }
+    }
+    pub fn retry(&self) -> &Retry {
+        &self.retry
+    }
+    /// Upstream the client is pinned to by its cookie, None without one or when it is down
+    pub fn pinned(&self, fields: &Fields) -> Option<net::SocketAddr> {
+        let addr = self.balancer.sticky.as_ref()?.upstream(fields)?;
+        let available = self.balancer.addrs.contains(&addr) && self.balancer.available(addr);
+        if available {
+            self.balancer.claim_trial(addr);
+        }
+        available.then_some(addr)
+    }
+    /// `Set-Cookie` line pinning the client to `addr`, None when it is pinned there already
+    /// or sessions are not sticky
+    pub fn pin(&self, addr: net::SocketAddr, fields: &Fields) -> Option<Vec<u8>> {
+        self.balancer.sticky.as_ref()?.set_cookie(addr, fields)
+    }
+    /// Count a request to `addr` as in flight until the guard is dropped,
+    /// None when the balancing does not need it
+    pub fn track(&self, addr: net::SocketAddr) -> Option<Active> {
+        match &self.balancer.balance {
+            Balance::LeastConn(load) | Balance::P2cEwma(load) => load.start(addr),
+            Balance::RoundRobin | Balance::Hash(..) => None,
+        }
+    }
+    /// Count the outcome of a request to upstream, for outlier detection, and how long
+    /// it took until the response head, for latency-aware balancing
+    pub fn report(&self, addr: net::SocketAddr, passed: bool, latency: Duration) {
+        if let Balance::P2cEwma(load) = &self.balancer.balance {
+            // a failure weighs as a slow response, so upstreams failing fast do not draw requests
+            load.record(
+                addr,
+                if passed {
+                    latency
+                } else {
+                    latency.max(FAILURE_LATENCY)
+                },
+            );
+        }
+        let outlier = match &self.balancer.outlier {
+            Some(x) => x,
+            None => return,
+        };
+        match outlier.record(addr, passed) {
+            Some(Duration::ZERO) => println!("upstream {} is back", addr),
+            Some(x) => println!("upstream {} is ejected for {:?}", addr, x),
+            None => {}
+        }
+    }
+    /// Limits of reading the whole request body before sending it, None to stream it
+    pub fn buffer_request(&self) -> Option<&Buffer> {
+        self.buffer_request.as_ref()
+    }
+    /// Idle connections to upstreams, None when each request has its own
+    pub fn pool(&self) -> Option<&Arc<Pool>> {
+        self.pool.as_ref()
+    }
+    /// Limits of reading the whole response before sending it, None to send it as it comes
+    pub fn buffer_response(&self) -> Option<&Buffer> {
+        self.buffer_response.as_ref()
+    }
+    /// Returns the rewritten start line, or None if the target is left untouched
+    pub fn rewrite(&self, startline: &StartLine, captures: &Captures) -> Option<StartLine> {
+        if self.rewrite.is_empty() {
+            return None;
+        }
+        let (authority, origin) = startline.split_target();
+        if !origin.starts_with(b"/") {
+            return None;
+        }
+        let path = [authority, &self.rewrite.apply(origin, captures)].concat();
+        if path == startline.path {
+            return None;
+        }
+        Some(StartLine {
+            path,
+            ..startline.clone()
+        })
+    }
+    /// Returns header fields to be set on the upstream request
+    pub fn headers(&self, captures: &Captures) -> Vec<(String, String)> {
+        self.headers
+            .iter()
+            .map(|(name, value)| (name.clone(), captures.expand(value)))
+            .collect()
+    }
+    /// Returns how the response should be compressed, None if compression is off
+    pub fn compression(&self, startline: &StartLine, fields: &Fields) -> Option<Compression<'_>> {
+        self.compress
+            .as_ref()
+            .map(|x| x.negotiate(startline, fields))
+    }
+}
+
+/// How the upstream of a request is picked among the ones of the route
+#[derive(Debug, Clone)]
+enum Balance {
+    // smooth weighted round-robin
+    RoundRobin,
+    // fewest requests in flight for the weight, counted across the routes of host
+    LeastConn(Arc<Load>),
+    // consistent hashing of a key of the request over the upstreams of the route,
+    // round-robin for requests without the key
+    Hash(HashKey, Ring),
+    // the cheaper of two upstreams sampled at random, by average latency and requests in flight
+    P2cEwma(Arc<Load>),
+}
+
+// latency counted for a request failing upstream
+const FAILURE_LATENCY: Duration = Duration::from_secs(1);
+
+/// Part of the request hashed to pick the upstream
+#[derive(Debug, Clone, PartialEq)]
+enum HashKey {
+    ClientIp,
+    Header(String),
+    Cookie(String),
+    // without the query
+    Path,
+}
+
+/// Upstreams of a route with their weights
 #[derive(Debug)]
 struct Balancer {
-    counter: atomic::AtomicUsize,
     addrs: Vec<net::SocketAddr>,
+    weights: Vec<i64>,
+    // how far each upstream is behind its share in round-robin
+    current: Mutex<Vec<i64>>,
+    // set by host
+    balance: Balance,
+    health: Option<Arc<Health>>,
+    outlier: Option<Arc<Outlier>>,
+    sticky: Option<Arc<Affinity>>,
     #[cfg(debug_assertions)]
     domain: String,
 }
 
 impl Balancer {
-    fn route(&self) -> net::SocketAddr {
-        let mut counter = self.counter.fetch_add(1, Ordering::Release);
-        counter %= self.addrs.len();
-        self.addrs[counter]
+    // the ring of hash balancing is over the upstreams of the route
+    fn set_balance(&mut self, balance: &Balance) {
+        self.balance = match balance {
+            Balance::Hash(key, _) => {
+                let upstreams: Vec<_> = self
+                    .addrs
+                    .iter()
+                    .copied()
+                    .zip(self.weights.iter().copied())
+                    .collect();
+                Balance::Hash(key.clone(), Ring::new(&upstreams))
+            }
+            x => x.clone(),
+        };
+    }
+
+    fn next(&self, tried: &[net::SocketAddr], key: Option<&[u8]>) -> net::SocketAddr {
+        let mut candidates: Vec<usize> = (0..self.addrs.len())
+            .filter(|&i| !tried.contains(&self.addrs[i]))
+            .collect();
+        // every one tried, taken in turn again
+        if candidates.is_empty() {
+            candidates = (0..self.addrs.len()).collect();
+        }
+        let addr = match &self.balance {
+            Balance::Hash(_, ring) if key.is_some() => {
+                let order = ring
+                    .walk(key.unwrap())
+                    .filter(|x| candidates.iter().any(|&i| self.addrs[i] == *x));
+                self.fallback(order).unwrap()
+            }
+            Balance::P2cEwma(load) => {
+                load::shuffle(&mut candidates);
+                let mut sampled = candidates
+                    .iter()
+                    .copied()
+                    .filter(|&i| self.available(self.addrs[i]));
+                match (sampled.next(), sampled.next()) {
+                    (Some(a), Some(b)) => {
+                        let cost = |i: usize| load.cost(self.addrs[i], self.weights[i]);
+                        self.addrs[if cost(b) < cost(a) { b } else { a }]
+                    }
+                    _ => self
+                        .fallback(candidates.iter().map(|&i| self.addrs[i]))
+                        .unwrap(),
+                }
+            }
+            Balance::RoundRobin | Balance::Hash(..) => self.round_robin(candidates),
+            Balance::LeastConn(load) => {
+                let mut candidates: Vec<_> = candidates
+                    .into_iter()
+                    .map(|i| (self.addrs[i], self.weights[i]))
+                    .collect();
+                load.order(&mut candidates);
+                self.fallback(candidates.iter().map(|x| x.0)).unwrap()
+            }
+        };
+        self.claim_trial(addr);
+        addr
+    }
+
+    // the trial of an upstream back from ejection goes to the request it is picked for
+    fn claim_trial(&self, addr: net::SocketAddr) {
+        if let Some(outlier) = &self.outlier {
+            outlier.claim_trial(addr);
+        }
+    }
+
+    /// First upstream of `order` available, or else the first one, None when `order` is empty
+    ///
+    /// When every upstream is down, trying one is better than none.
+    fn fallback(
+        &self,
+        order: impl IntoIterator<Item = net::SocketAddr>,
+    ) -> Option<net::SocketAddr> {
+        let mut order = order.into_iter().peekable();
+        let first = *order.peek()?;
+        Some(order.find(|x| self.available(*x)).unwrap_or(first))
+    }
+
+    fn available(&self, addr: net::SocketAddr) -> bool {
+        self.health.as_ref().is_none_or(|x| x.healthy(addr))
+            && self.outlier.as_ref().is_none_or(|x| x.available(addr))
+    }
+
+    fn round_robin(&self, mut candidates: Vec<usize>) -> net::SocketAddr {
+        let mut current = self.current.lock().unwrap();
+        let total: i64 = candidates.iter().map(|&i| self.weights[i]).sum();
+        for &i in candidates.iter() {
+            current[i] += self.weights[i];
+        }
+        candidates.sort_by_key(|&i| -current[i]);
+
+        let addr = self
+            .fallback(candidates.iter().map(|&i| self.addrs[i]))
+            .unwrap();
+        let chosen = candidates
+            .iter()
+            .position(|&i| self.addrs[i] == addr)
+            .unwrap();
+        // the ones skipped for being down do not build up a share meanwhile
+        for &i in &candidates[..chosen] {
+            current[i] -= self.weights[i];
+        }
+        let chosen = candidates[chosen];
+        current[chosen] -= total;
+        self.addrs[chosen]
     }
 }
 
-pub fn hash<T>(obj: T) -> u64
-where
-    T: Hash,
-{
-    let mut hasher = DefaultHasher::new();
-    obj.hash(&mut hasher);
-    hasher.finish()
+// entry of `routing`, either `- <addr>` or `- addr: <addr>` with `weight`
+struct Upstream(net::SocketAddr, i64);
+
+impl TryFrom<&level::Level> for Upstream {
+    type Error = level::Error;
+
+    fn try_from(level: &level::Level) -> Result<Self, Self::Error> {
+        let (domain, weight) = match level {
+            level::Level::List(x) => (x, 1),
+            _ => {
+                let weight = match level.value(vec!["weight"]) {
+                    Ok(x) => x.try_into()?,
+                    Err(_) => 1,
+                };
+                (level.value(vec!["addr"])?, weight)
+            }
+        };
+        if weight < 1 {
+            return Err(level::Error::MisMatchType);
+        }
+        let domain: String = domain.try_into()?;
+        let addr = domain
+            .to_socket_addrs()
+            .expect(&format!("fail parsing domain {:?}", domain))
+            .next()
+            .unwrap();
+        Ok(Upstream(addr, weight))
+    }
 }
 
-struct Host(u64, Balancer);
+struct Host(String, Vec<Route>);
 
 impl TryFrom<&level::Level> for Host {
     type Error = level::Error;
 
     fn try_from(level: &level::Level) -> Result<Self, Self::Error> {
-        let val = level.field_name(vec![])?;
-        let hashed_domain = hash(val.as_bytes());
+        // `"*.example.com":` keeps yml happy with the wildcard
+        let val = level.field_name(vec![])?.trim_matches('"');
+        let name = if val.starts_with('^') {
+            val.to_string()
+        } else {
+            let name = val.to_ascii_lowercase();
+            name.strip_suffix('.').unwrap_or(&name).to_string()
+        };
 
-        let routing = level.list(vec!["routing"])?;
+        let mut routes = match level.level(vec!["routes"]) {
+            Ok(_) => level.struct_list(vec!["routes"])?,
+            Err(_) => vec![],
+        };
+        // settings of host itself act as the last route
+        let fallback = ["routing", "redirect", "return", "root"]
+            .into_iter()
+            .any(|x| level.level(vec![x]).is_ok());
+        if fallback {
+            routes.push(level.try_into()?);
+        }
+
+        let force_https = match level.value(vec!["force-https"]) {
+            Ok(x) => x.try_into()?,
+            Err(_) => false,
+        };
+        if force_https {
+            routes.insert(
+                0,
+                Route {
+                    matcher: Matcher::insecure(),
+                    action: Action::Redirect(Redirect::https()),
+                    hsts: None,
+                    cache: None,
+                    purge: vec![],
+                },
+            );
+        }
+        if let Ok(x) = level.value(vec!["hsts"]) {
+            let hsts: String = x.try_into()?;
+            for route in routes.iter_mut() {
+                route.hsts = Some(hsts.clone());
+            }
+        }
+
+        // upstreams of every route, each once
+        let mut addrs: Vec<net::SocketAddr> = vec![];
+        for route in routes.iter() {
+            if let Action::Proxy(proxy) = &route.action {
+                for addr in proxy.balancer.addrs.iter() {
+                    if !addrs.contains(addr) {
+                        addrs.push(*addr);
+                    }
+                }
+            }
+        }
+        if let Some(check) = health(level, &name)? {
+            let health = Arc::new(Health::new(check, addrs.clone()));
+            for route in routes.iter_mut() {
+                if let Action::Proxy(proxy) = &mut route.action {
+                    proxy.balancer.health = Some(health.clone());
+                }
+            }
+        }
+        let balance = balance(level, &addrs)?;
+        for route in routes.iter_mut() {
+            if let Action::Proxy(proxy) = &mut route.action {
+                proxy.balancer.set_balance(&balance);
+            }
+        }
+        if let Some(sticky) = sticky(level)? {
+            let affinity = Arc::new(Affinity::new(sticky, addrs.clone()));
+            for route in routes.iter_mut() {
+                if let Action::Proxy(proxy) = &mut route.action {
+                    proxy.balancer.sticky = Some(affinity.clone());
+                }
+            }
+        }
+        if let Some(eject) = outlier(level)? {
+            let outlier = Arc::new(Outlier::new(eject, addrs));
+            for route in routes.iter_mut() {
+                if let Action::Proxy(proxy) = &mut route.action {
+                    proxy.balancer.outlier = Some(outlier.clone());
+                }
+            }
+        }
+
+        if let Some(buffer) = buffer(level, "buffer-response")? {
+            for route in routes.iter_mut() {
+                if let Action::Proxy(proxy) = &mut route.action {
+                    proxy.buffer_response = Some(buffer.clone());
+                }
+            }
+        }
+
+        if let Some(cache) = cache(level)? {
+            let cache = Arc::new(cache);
+            let purge = level
+                .list(vec!["cache", "purge"])
+                .unwrap_or_default()
+                .into_iter()
+                .map(|x| {
+                    let network: String = x.try_into()?;
+                    Ok(network
+                        .parse()
+                        .unwrap_or_else(|_| panic!("fail parsing network {:?}", network)))
+                })
+                .collect::<Result<Vec<Network>, level::Error>>()?;
+            for route in routes.iter_mut() {
+                route.cache = Some(cache.clone());
+                route.purge = purge.clone();
+            }
+        }
 
-        let addrs: Vec<net::SocketAddr> = routing
+        Ok(Host(name, routes))
+    }
+}
+
+impl TryFrom<&level::Level> for Route {
+    type Error = level::Error;
+
+    fn try_from(level: &level::Level) -> Result<Self, Self::Error> {
+        Ok(Route {
+            matcher: level.try_into()?,
+            action: level.try_into()?,
+            hsts: None,
+            cache: None,
+            purge: vec![],
+        })
+    }
+}
+
+impl TryFrom<&level::Level> for Proxy {
+    type Error = level::Error;
+
+    fn try_from(level: &level::Level) -> Result<Self, Self::Error> {
+        let headers = level
+            .list(vec!["set-header"])
+            .unwrap_or_default()
             .into_iter()
-            .map(|d| {
-                let domain: String = d.try_into().unwrap();
-                domain
-                    .to_socket_addrs()
-                    .expect(&format!("fail parsing domain {:?}", domain))
-                    .next()
-                    .unwrap()
+            .map(|header| {
+                let header: String = header.try_into()?;
+                let (name, value) = header
+                    .split_once(':')
+                    .ok_or(level::Error::MisMatchStructure)?;
+                Ok((name.trim().to_string(), value.trim().to_string()))
             })
-            .collect();
+            .collect::<Result<Vec<_>, level::Error>>()?;
+
+        let balancer: Balancer = level.try_into()?;
+        let retry = retry(level, balancer.addrs.len())?;
+        Ok(Proxy {
+            balancer,
+            rewrite: level.try_into()?,
+            headers,
+            compress: compress(level)?,
+            retry,
+            buffer_request: buffer(level, "buffer-request")?,
+            buffer_response: None,
+            pool: keepalive(level)?.map(|x| Arc::new(Pool::new(x))),
+        })
+    }
+}
 
-        let balancer = Balancer {
-            counter: atomic::AtomicUsize::new(0),
-            addrs,
+/// Parse `cache: true`, or `cache` with `size`, `max-object`, stale windows, `coalesce-timeout` and `disk`
+fn cache(level: &level::Level) -> Result<Option<Cache>, level::Error> {
+    const SIZE: u64 = 64 << 20;
+    const MAX_OBJECT: u64 = 1 << 20;
+    const DISK_SIZE: u64 = 1 << 30;
+    const DISK_MAX_OBJECT: u64 = 256 << 20;
+    const COALESCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    if let Ok(x) = level.value(vec!["cache"]) {
+        let enabled: bool = x.try_into()?;
+        return Ok(enabled.then(|| Cache::new(SIZE, MAX_OBJECT).coalesce(COALESCE_TIMEOUT)));
+    }
+    if level.level(vec!["cache"]).is_err() {
+        return Ok(None);
+    }
+    let size = match level.value(vec!["cache", "size"]) {
+        Ok(x) => x.size()?,
+        Err(_) => SIZE,
+    };
+    let max_object = match level.value(vec!["cache", "max-object"]) {
+        Ok(x) => x.size()?,
+        Err(_) => MAX_OBJECT.min(size),
+    };
+    let stale = |name| match level.value(vec!["cache", name]) {
+        Ok(x) => x.duration(),
+        Err(_) => Ok(Duration::ZERO),
+    };
+    let coalesce = match level.value(vec!["cache", "coalesce-timeout"]) {
+        Ok(x) => x.duration()?,
+        Err(_) => COALESCE_TIMEOUT,
+    };
+    let mut cache = Cache::new(size, max_object)
+        .stale(stale("stale-while-revalidate")?, stale("stale-if-error")?)
+        .coalesce(coalesce);
+
+    if let Ok(x) = level.value(vec!["cache", "disk", "path"]) {
+        let path: String = x.try_into()?;
+        let size = match level.value(vec!["cache", "disk", "size"]) {
+            Ok(x) => x.size()?,
+            Err(_) => DISK_SIZE,
+        };
+        let max_object = match level.value(vec!["cache", "disk", "max-object"]) {
+            Ok(x) => x.size()?,
+            Err(_) => DISK_MAX_OBJECT.min(size),
+        };
+        let disk = Disk::open(path::Path::new(&path), size, max_object)
+            .unwrap_or_else(|x| panic!("fail opening cache directory {:?}: {}", path, x));
+        cache = cache.disk(disk);
+    }
+    Ok(Some(cache))
+}
+
+/// Parse `retry` with `count`, `timeout`, `backoff` and `status`
+///
+/// Without it, a failed connection is retried once on each of the other upstreams.
+fn retry(level: &level::Level, upstreams: usize) -> Result<Retry, level::Error> {
+    let mut retry = Retry {
+        count: upstreams.saturating_sub(1),
+        timeout: None,
+        backoff: Duration::ZERO,
+        statuses: vec![],
+    };
+    if let Ok(x) = level.value(vec!["retry", "count"]) {
+        let count: i64 = x.try_into()?;
+        retry.count = usize::try_from(count).map_err(|_| level::Error::MisMatchType)?;
+    }
+    if let Ok(x) = level.value(vec!["retry", "timeout"]) {
+        retry.timeout = Some(x.duration()?).filter(|x| !x.is_zero());
+    }
+    if let Ok(x) = level.value(vec!["retry", "backoff"]) {
+        retry.backoff = x.duration()?;
+    }
+    retry.statuses = level
+        .list(vec!["retry", "status"])
+        .unwrap_or_default()
+        .into_iter()
+        .map(|x| {
+            let status: i64 = x.try_into()?;
+            u16::try_from(status).map_err(|_| level::Error::MisMatchType)
+        })
+        .collect::<Result<Vec<_>, level::Error>>()?;
+    Ok(retry)
+}
+
+/// Parse `compress: true`, or `compress` with `min-size` and `types`
+fn compress(level: &level::Level) -> Result<Option<Compress>, level::Error> {
+    if let Ok(x) = level.value(vec!["compress"]) {
+        let enabled: bool = x.try_into()?;
+        return Ok(enabled.then(Compress::default));
+    }
+    if level.level(vec!["compress"]).is_err() {
+        return Ok(None);
+    }
+
+    let mut compress = Compress::default();
+    if let Ok(x) = level.value(vec!["compress", "min-size"]) {
+        let min_size: i64 = x.try_into()?;
+        compress.min_size = u64::try_from(min_size).map_err(|_| level::Error::MisMatchType)?;
+    }
+    let types = level.list(vec!["compress", "types"]).unwrap_or_default();
+    if !types.is_empty() {
+        compress.types = types
+            .into_iter()
+            .map(|x| {
+                let content_type: String = x.try_into()?;
+                Ok(content_type.to_ascii_lowercase())
+            })
+            .collect::<Result<Vec<_>, level::Error>>()?;
+    }
+    Ok(Some(compress))
+}
+
+/// Parse `keepalive: true`, or `keepalive` with `idle`, `idle-timeout`, `requests` and `lifetime`
+fn keepalive(level: &level::Level) -> Result<Option<KeepAlive>, level::Error> {
+    if let Ok(x) = level.value(vec!["keepalive"]) {
+        let enabled: bool = x.try_into()?;
+        return Ok(enabled.then(KeepAlive::default));
+    }
+    if level.level(vec!["keepalive"]).is_err() {
+        return Ok(None);
+    }
+
+    let mut keepalive = KeepAlive::default();
+    if let Ok(x) = level.value(vec!["keepalive", "idle"]) {
+        let idle: i64 = x.try_into()?;
+        keepalive.idle = usize::try_from(idle).map_err(|_| level::Error::MisMatchType)?;
+    }
+    if let Ok(x) = level.value(vec!["keepalive", "requests"]) {
+        let requests: i64 = x.try_into()?;
+        keepalive.requests = usize::try_from(requests).map_err(|_| level::Error::MisMatchType)?;
+    }
+    if let Ok(x) = level.value(vec!["keepalive", "idle-timeout"]) {
+        keepalive.idle_timeout = x.duration()?;
+    }
+    if let Ok(x) = level.value(vec!["keepalive", "lifetime"]) {
+        keepalive.lifetime = x.duration()?;
+    }
+    Ok(Some(keepalive))
+}
+
+/// Parse `health: true`, or `health` with `path`, `host`, `status`, `body`,
+/// `interval`, `timeout`, `rise` and `fall`
+///
+/// Probes are HTTP requests with `path`, otherwise connections only.
+fn health(level: &level::Level, name: &str) -> Result<Option<Check>, level::Error> {
+    if let Ok(x) = level.value(vec!["health"]) {
+        let enabled: bool = x.try_into()?;
+        return Ok(enabled.then(Check::default));
+    }
+    if level.level(vec!["health"]).is_err() {
+        return Ok(None);
+    }
+
+    let mut check = Check::default();
+    if let Ok(x) = level.value(vec!["health", "path"]) {
+        let path: String = x.try_into()?;
+        // patterns are no host to ask for
+        let host = match level.value(vec!["health", "host"]) {
+            Ok(x) => Some(x.try_into()?),
+            Err(_) if !name.starts_with('^') && !name.contains('*') => Some(name.to_string()),
+            Err(_) => None,
+        };
+        let status = match level.value(vec!["health", "status"]) {
+            Ok(x) => {
+                let status: String = match x {
+                    level::Value::Number(x) => x.to_string(),
+                    x => x.try_into()?,
+                };
+                let (low, high) = status.split_once('-').unwrap_or((&status, &status));
+                let parse = |x: &str| x.trim().parse().map_err(|_| level::Error::MisMatchType);
+                (parse(low)?, parse(high)?)
+            }
+            Err(_) => (200, 399),
+        };
+        let body = match level.value(vec!["health", "body"]) {
+            Ok(x) => Some(x.try_into()?),
+            Err(_) => None,
+        };
+        check.probe = Probe::Http {
+            path,
+            host,
+            status,
+            body,
+        };
+    }
+    if let Ok(x) = level.value(vec!["health", "interval"]) {
+        check.interval = x.duration()?;
+    }
+    if let Ok(x) = level.value(vec!["health", "timeout"]) {
+        check.timeout = x.duration()?;
+    }
+    if let Ok(x) = level.value(vec!["health", "rise"]) {
+        let rise: i64 = x.try_into()?;
+        check.rise = usize::try_from(rise).map_err(|_| level::Error::MisMatchType)?;
+    }
+    if let Ok(x) = level.value(vec!["health", "fall"]) {
+        let fall: i64 = x.try_into()?;
+        check.fall = usize::try_from(fall).map_err(|_| level::Error::MisMatchType)?;
+    }
+    Ok(Some(check))
+}
+
+/// Parse `balance`, round-robin when not given
+fn balance(level: &level::Level, addrs: &[net::SocketAddr]) -> Result<Balance, level::Error> {
+    let balance: String = match level.value(vec!["balance"]) {
+        Ok(x) => x.try_into()?,
+        Err(_) => return Ok(Balance::RoundRobin),
+    };
+    match balance.as_str() {
+        "round-robin" => Ok(Balance::RoundRobin),
+        "least-conn" => Ok(Balance::LeastConn(Arc::new(Load::new(addrs.to_vec())))),
+        "hash" => Ok(Balance::Hash(hash_key(level)?, Ring::default())),
+        "p2c-ewma" => Ok(Balance::P2cEwma(Arc::new(Load::new(addrs.to_vec())))),
+        _ => Err(level::Error::MisMatchType),
+    }
+}
+
+/// Parse `hash-key` as `client-ip`(default), `header <name>`, `cookie <name>` or `path`
+fn hash_key(level: &level::Level) -> Result<HashKey, level::Error> {
+    let key: String = match level.value(vec!["hash-key"]) {
+        Ok(x) => x.try_into()?,
+        Err(_) => return Ok(HashKey::ClientIp),
+    };
+    match key.split_once(' ') {
+        None if key == "client-ip" => Ok(HashKey::ClientIp),
+        None if key == "path" => Ok(HashKey::Path),
+        Some(("header", name)) => Ok(HashKey::Header(name.trim().to_string())),
+        Some(("cookie", name)) => Ok(HashKey::Cookie(name.trim().to_string())),
+        _ => Err(level::Error::MisMatchType),
+    }
+}
+
+/// Parse `sticky: true`, or `sticky` with `cookie`, `secret` and `max-age`
+fn sticky(level: &level::Level) -> Result<Option<Sticky>, level::Error> {
+    let new = || Sticky::new().unwrap_or_else(|x| panic!("fail making secret of sticky: {}", x));
+    if let Ok(x) = level.value(vec!["sticky"]) {
+        let enabled: bool = x.try_into()?;
+        return Ok(enabled.then(new));
+    }
+    if level.level(vec!["sticky"]).is_err() {
+        return Ok(None);
+    }
+
+    let mut sticky = new();
+    if let Ok(x) = level.value(vec!["sticky", "cookie"]) {
+        sticky.cookie = x.try_into()?;
+    }
+    if let Ok(x) = level.value(vec!["sticky", "secret"]) {
+        let secret: String = x.try_into()?;
+        sticky.secret = secret.into_bytes();
+    }
+    if let Ok(x) = level.value(vec!["sticky", "max-age"]) {
+        sticky.max_age = Some(x.duration()?);
+    }
+    Ok(Some(sticky))
+}
+
+/// Parse `outlier: true`, or `outlier` with `failures`, `ejection`, `max-ejection` and `max-percent`
+fn outlier(level: &level::Level) -> Result<Option<Eject>, level::Error> {
+    if let Ok(x) = level.value(vec!["outlier"]) {
+        let enabled: bool = x.try_into()?;
+        return Ok(enabled.then(Eject::default));
+    }
+    if level.level(vec!["outlier"]).is_err() {
+        return Ok(None);
+    }
+
+    let mut eject = Eject::default();
+    if let Ok(x) = level.value(vec!["outlier", "failures"]) {
+        let failures: i64 = x.try_into()?;
+        eject.failures = usize::try_from(failures).map_err(|_| level::Error::MisMatchType)?;
+    }
+    if let Ok(x) = level.value(vec!["outlier", "ejection"]) {
+        eject.ejection = x.duration()?;
+    }
+    if let Ok(x) = level.value(vec!["outlier", "max-ejection"]) {
+        eject.max_ejection = x.duration()?;
+    }
+    if let Ok(x) = level.value(vec!["outlier", "max-percent"]) {
+        let percent: i64 = x.try_into()?;
+        eject.max_percent = usize::try_from(percent).map_err(|_| level::Error::MisMatchType)?;
+    }
+    Ok(Some(eject))
+}
+
+/// Parse `<key>: true`, or `<key>` with `memory` and `max` sizes
+fn buffer(level: &level::Level, key: &str) -> Result<Option<Buffer>, level::Error> {
+    if let Ok(x) = level.value(vec![key]) {
+        let enabled: bool = x.try_into()?;
+        return Ok(enabled.then(Buffer::default));
+    }
+    if level.level(vec![key]).is_err() {
+        return Ok(None);
+    }
+
+    let mut buffer = Buffer::default();
+    if let Ok(x) = level.value(vec![key, "memory"]) {
+        buffer.memory = x.size()?;
+    }
+    if let Ok(x) = level.value(vec![key, "max"]) {
+        buffer.max = x.size()?;
+    }
+    Ok(Some(buffer))
+}
+
+impl TryFrom<&level::Level> for Balancer {
+    type Error = level::Error;
+
+    fn try_from(level: &level::Level) -> Result<Self, Self::Error> {
+        let routing: Vec<Upstream> = level.struct_list(vec!["routing"])?;
+
+        Ok(Balancer {
+            addrs: routing.iter().map(|x| x.0).collect(),
+            weights: routing.iter().map(|x| x.1).collect(),
+            current: Mutex::new(vec![0; routing.len()]),
+            balance: Balance::RoundRobin,
+            health: None,
+            outlier: None,
+            sticky: None,
             #[cfg(debug_assertions)]
-            domain: val.to_string(),
+            domain: level.field_name(vec![])?.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn proxy<'a>(state: &'a AppState, host: &str, target: &[u8]) -> &'a Proxy {
+        let startline: StartLine = target.try_into().unwrap();
+        match &state
+            .route(host, &startline, &Fields::new())
+            .unwrap()
+            .0
+            .action
+        {
+            Action::Proxy(x) => x,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn retry() {
+        let state = AppState::new("test/routesyml");
+        let upload = proxy(&state, "a.example.com", b"PUT /upload HTTP/1.1");
+        let expected = Retry {
+            count: 3,
+            timeout: Some(Duration::from_secs(2)),
+            backoff: Duration::from_millis(100),
+            statuses: vec![502, 503],
+        };
+        assert_eq!(upload.retry(), &expected);
+        let first = upload.next_upstream(&[], None);
+        let second = upload.next_upstream(&[first], None);
+        assert_ne!(first, second);
+        // every one tried, taken in turn again
+        upload.next_upstream(&[first, second], None);
+
+        let root = proxy(&state, "a.example.com", b"GET / HTTP/1.1");
+        assert_eq!(root.retry().count, 0);
+    }
+
+    #[test]
+    fn buffer_request() {
+        let state = AppState::new("test/routesyml");
+        let limit = proxy(&state, "a.example.com", b"PUT /upload HTTP/1.1")
+            .buffer_request()
+            .unwrap();
+        assert_eq!((limit.memory, limit.max), (64 << 10, 16 << 20));
+        assert!(proxy(&state, "a.example.com", b"GET / HTTP/1.1")
+            .buffer_request()
+            .is_none());
+    }
+
+    #[test]
+    fn buffer_response() {
+        let state = AppState::new("test/routesyml");
+        // set on host, for every route
+        let limit = proxy(&state, "a.example.com", b"GET / HTTP/1.1")
+            .buffer_response()
+            .unwrap();
+        assert_eq!((limit.memory, limit.max), (1 << 20, 8 << 20));
+        let upload = proxy(&state, "a.example.com", b"PUT /upload HTTP/1.1");
+        assert_eq!(upload.buffer_response(), Some(limit));
+    }
+
+    #[test]
+    fn pool() {
+        let state = AppState::new("test/routesyml");
+        let upload = proxy(&state, "a.example.com", b"PUT /upload HTTP/1.1");
+        let pool = upload.pool().unwrap();
+        assert!(pool.get(upload.next_upstream(&[], None)).is_none());
+        assert!(proxy(&state, "a.example.com", b"GET / HTTP/1.1")
+            .pool()
+            .is_none());
+    }
+
+    #[test]
+    fn health() {
+        let state = AppState::new("test/routesyml");
+        let upload = proxy(&state, "a.example.com", b"PUT /upload HTTP/1.1");
+        let first = upload.next_upstream(&[], None);
+        let second = upload.next_upstream(&[first], None);
+
+        // every upstream of the host is checked, the ones down skipped
+        let checks = state.health_checks();
+        assert_eq!(checks.len(), 1);
+        assert!(checks[0].record(first, false).is_some());
+        assert!(!(0..4).any(|_| upload.next_upstream(&[], None) == first));
+        // unless nothing else is left
+        assert_eq!(upload.next_upstream(&[second], None), first);
+    }
+
+    #[test]
+    fn outlier() {
+        let state = AppState::new("test/routesyml");
+        let upload = proxy(&state, "a.example.com", b"PUT /upload HTTP/1.1");
+        let first = upload.next_upstream(&[], None);
+        let second = upload.next_upstream(&[first], None);
+
+        // up to health checks, but ejected after failing real traffic
+        upload.report(first, false, Duration::ZERO);
+        assert!(!(0..4).any(|_| upload.next_upstream(&[], None) == first));
+        assert_eq!(upload.next_upstream(&[second], None), first);
+    }
+
+    #[test]
+    fn cache_coalescing() {
+        let state = AppState::new("test/routesyml");
+        let startline: StartLine = b"GET / HTTP/1.1".as_ref().try_into().unwrap();
+        let (route, _) = state
+            .route("cache.example.com", &startline, &Fields::new())
+            .unwrap();
+        let cache = route.cache().unwrap();
+
+        // a miss in flight holds the others back until it is done
+        let fetch = cache.fetch_claim("a").unwrap();
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| cache.wait("a"));
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!waiter.is_finished());
+            drop(fetch);
+            assert!(waiter.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn weighted() {
+        let state = AppState::new("test/routesyml");
+        let upload = proxy(&state, "a.example.com", b"PUT /upload HTTP/1.1");
+        let heavy: net::SocketAddr = "127.0.0.1:8007".parse().unwrap();
+        let picks: Vec<bool> = (0..8)
+            .map(|_| upload.next_upstream(&[], None) == heavy)
+            .collect();
+        // three times as often, spread out rather than in a row
+        assert_eq!(picks.iter().filter(|x| **x).count(), 6);
+        assert!(!picks.windows(4).any(|x| x.iter().all(|y| *y)));
+        assert_ne!(upload.next_upstream(&[heavy], None), heavy);
+    }
+
+    #[test]
+    fn least_conn() {
+        let state = AppState::new("test/routesyml");
+        let proxy = proxy(&state, "d.example.com", b"GET / HTTP/1.1");
+        let light: net::SocketAddr = "127.0.0.1:8008".parse().unwrap();
+        let heavy: net::SocketAddr = "127.0.0.1:8009".parse().unwrap();
+        // in flight until the guards are dropped, twice as many for twice the weight
+        let mut active = vec![];
+        for _ in 0..6 {
+            let addr = proxy.next_upstream(&[], None);
+            active.push((addr, proxy.track(addr).unwrap()));
+        }
+        let on_heavy = active.iter().filter(|(x, _)| *x == heavy).count();
+        assert_eq!(on_heavy, 4);
+        active.retain(|(x, _)| *x == light);
+        assert_eq!(proxy.next_upstream(&[], None), heavy);
+        assert_eq!(proxy.next_upstream(&[heavy], None), light);
+    }
+
+    #[test]
+    fn sticky() {
+        let state = AppState::new("test/routesyml");
+        let proxy = proxy(&state, "g.example.com", b"GET / HTTP/1.1");
+        let second: net::SocketAddr = "127.0.0.1:8016".parse().unwrap();
+
+        // pinned by the cookie set on the first response, whatever the turn
+        let line = proxy.pin(second, &Fields::new()).unwrap();
+        let line = String::from_utf8(line).unwrap();
+        assert!(line.starts_with("Set-Cookie: backend="));
+        assert!(line.contains("Max-Age=3600"));
+        let mut fields = Fields::new();
+        fields.push(
+            line.split(';')
+                .next()
+                .unwrap()
+                .replace("Set-Cookie", "Cookie")
+                .as_bytes(),
+        );
+        assert_eq!(proxy.pinned(&fields), Some(second));
+        assert!(proxy.pin(second, &fields).is_none());
+        assert!(proxy.pinned(&Fields::new()).is_none());
+    }
+
+    #[test]
+    fn hash() {
+        let state = AppState::new("test/routesyml");
+        let startline: StartLine = b"GET /a?b=c HTTP/1.1".as_slice().try_into().unwrap();
+        let proxy = proxy(&state, "e.example.com", b"GET /a?b=c HTTP/1.1");
+        let mut fields = Fields::new();
+        fields.push(b"Cookie: theme=dark; session=abc");
+        let key = proxy.hash_key(&startline, &fields, None).unwrap();
+        assert_eq!(key, b"abc");
+
+        // the same key, the same upstream
+        let addr = proxy.next_upstream(&[], Some(&key));
+        assert!((0..8).all(|_| proxy.next_upstream(&[], Some(&key)) == addr));
+        assert_ne!(proxy.next_upstream(&[addr], Some(&key)), addr);
+        // keys spread over the upstreams
+        let mut seen = vec![];
+        for i in 0..64 {
+            let addr = proxy.next_upstream(&[], Some(format!("{}", i).as_bytes()));
+            if !seen.contains(&addr) {
+                seen.push(addr);
+            }
+        }
+        assert_eq!(seen.len(), 3);
+
+        // without the key, taken in turn
+        assert!(proxy.hash_key(&startline, &Fields::new(), None).is_none());
+        let first = proxy.next_upstream(&[], None);
+        assert_ne!(proxy.next_upstream(&[], None), first);
+    }
+
+    #[test]
+    fn p2c_ewma() {
+        let state = AppState::new("test/routesyml");
+        let proxy = proxy(&state, "f.example.com", b"GET / HTTP/1.1");
+        let fast: net::SocketAddr = "127.0.0.1:8013".parse().unwrap();
+        let slow: net::SocketAddr = "127.0.0.1:8014".parse().unwrap();
+        proxy.report(fast, true, Duration::from_millis(10));
+        proxy.report(slow, true, Duration::from_millis(100));
+        assert!((0..8).all(|_| proxy.next_upstream(&[], None) == fast));
+
+        // busy enough to cost more than the slow one
+        let active: Vec<_> = (0..10).map(|_| proxy.track(fast).unwrap()).collect();
+        assert_eq!(proxy.next_upstream(&[], None), slow);
+        drop(active);
+        // failing fast is no better than slow
+        proxy.report(fast, false, Duration::from_millis(1));
+        assert_eq!(proxy.next_upstream(&[], None), slow);
+    }
+
+    #[test]
+    fn p2c_trial() {
+        let state = AppState::new("test/routesyml");
+        let proxy = proxy(&state, "h.example.com", b"GET / HTTP/1.1");
+        let slow: net::SocketAddr = "127.0.0.1:8017".parse().unwrap();
+        let fast: net::SocketAddr = "127.0.0.1:8018".parse().unwrap();
+        let outlier = proxy.balancer.outlier.as_ref().unwrap();
+        proxy.report(slow, false, Duration::ZERO);
+        proxy.report(fast, true, Duration::from_millis(1));
+        assert!(!outlier.available(slow));
+        outlier.expire(slow);
+
+        // sampled along with a cheaper one, the upstream ejected keeps its trial
+        for _ in 0..4 {
+            assert_eq!(proxy.next_upstream(&[], None), fast);
+        }
+        assert!(outlier.available(slow));
+        // which goes to the request it is picked for
+        assert_eq!(proxy.next_upstream(&[fast], None), slow);
+        assert!(!outlier.available(slow));
+    }
+
+    #[test]
+    fn routes() {
+        let state = AppState::new("test/routesyml");
+        let mut fields = Fields::new();
+        let route = |host: &str, startline: &[u8], fields: &Fields| {
+            let startline: StartLine = startline.try_into().unwrap();
+            let (route, captures) = state.route(host, &startline, fields).unwrap();
+            let route = match &route.action {
+                Action::Proxy(x) => x,
+                _ => unreachable!(),
+            };
+            let path = route
+                .rewrite(&startline, &captures)
+                .map(|x| x.path)
+                .unwrap_or(startline.path);
+            (
+                route.next_upstream(&[], None).port(),
+                path,
+                route.headers(&captures),
+            )
         };
 
-        Ok(Host(hashed_domain, balancer))
+        let (port, path, _) = route("a.example.com", b"GET /u/42/avatar HTTP/1.1", &fields);
+        assert_eq!((port, path), (8001, b"/avatar?user=42".to_vec()));
+
+        let (port, path, _) = route("a.example.com", b"GET /api/users HTTP/1.1", &fields);
+        assert_eq!((port, path), (8002, b"/users".to_vec()));
+
+        fields.push(b"X-Api-Version: 2");
+        let (port, _, _) = route("a.example.com", b"GET /api/users HTTP/1.1", &fields);
+        assert_eq!(port, 8004);
+        let (port, _, _) = route("a.example.com", b"POST /api/users HTTP/1.1", &fields);
+        assert_eq!(port, 8002);
+
+        let (port, path, _) = route("a.example.com", b"GET /u/me/avatar HTTP/1.1", &fields);
+        assert_eq!((port, path), (8000, b"/u/me/avatar".to_vec()));
+
+        let startline: StartLine = b"GET /healthz HTTP/1.1".as_ref().try_into().unwrap();
+        match &state
+            .route("a.example.com", &startline, &fields)
+            .unwrap()
+            .0
+            .action
+        {
+            Action::Return(x) => assert_eq!((x.status, x.body.as_slice()), (200, &b"ok"[..])),
+            _ => panic!("healthz should return directly"),
+        }
+        let startline: StartLine = b"GET /old/a?b=c HTTP/1.1".as_ref().try_into().unwrap();
+        match &state
+            .route("a.example.com", &startline, &fields)
+            .unwrap()
+            .0
+            .action
+        {
+            Action::Redirect(x) => {
+                let response = x.response(&startline, &Captures::new());
+                assert_eq!(response.status, 301);
+                assert_eq!(response.headers[0].1, "https://b.example.com/old/a?b=c");
+            }
+            _ => panic!("old should be redirected"),
+        }
+        let startline: StartLine = b"GET / HTTP/1.1".as_ref().try_into().unwrap();
+        match &state
+            .route("b.example.com", &startline, &fields)
+            .unwrap()
+            .0
+            .action
+        {
+            Action::Return(x) => assert_eq!(x.status, 404),
+            _ => panic!("host without routing should return directly"),
+        }
+
+        let startline: StartLine = b"GET /a?b HTTP/1.1".as_ref().try_into().unwrap();
+        let (taken, captures) = state.route("c.example.com", &startline, &fields).unwrap();
+        match &taken.action {
+            Action::Redirect(x) => {
+                let response = x.response(&startline, &captures);
+                assert_eq!(response.status, 308);
+                assert_eq!(response.headers[0].1, "https://c.example.com/a?b");
+            }
+            _ => panic!("plain http should be redirected"),
+        }
+        let mut secure = Fields::new();
+        secure.push(b"X-Forwarded-Proto: https");
+        let (taken, _) = state.route("c.example.com", &startline, &secure).unwrap();
+        assert!(matches!(taken.action, Action::Proxy(_)));
+        assert_eq!(
+            taken.response_headers(true),
+            vec![(
+                "Strict-Transport-Security".to_string(),
+                "max-age=31536000".to_string()
+            )]
+        );
+
+        let (port, _, headers) = route("tenant-abc.example.com", b"GET / HTTP/1.1", &fields);
+        assert_eq!(port, 8003);
+        assert_eq!(headers, vec![("X-Tenant".to_string(), "abc".to_string())]);
+
+        assert!(state.trusted("10.0.0.1".parse().unwrap()));
+        assert!(!state.trusted("192.0.2.1".parse().unwrap()));
     }
 }
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 #[derive(Debug)]
 pub enum Error {
     Unknown,
@@ -106,6 +108,50 @@ impl Level {
     }
 }
 
+impl Value {
+    /// Size in bytes, a number or a string with unit(`512KiB`, `64MiB`, `1GiB`)
+    pub fn size(&self) -> Result<u64, Error> {
+        match self {
+            Value::Number(x) if *x >= 0.0 => Ok(*x as u64),
+            Value::String(x) => {
+                let split = x.find(|c: char| !c.is_ascii_digit()).unwrap_or(x.len());
+                let (number, unit) = x.split_at(split);
+                let number: u64 = number.parse().map_err(|_| Error::MisMatchType)?;
+                let unit = match unit.trim().to_ascii_lowercase().as_str() {
+                    "" | "b" => 1,
+                    "k" | "kb" | "kib" => 1 << 10,
+                    "m" | "mb" | "mib" => 1 << 20,
+                    "g" | "gb" | "gib" => 1 << 30,
+                    _ => return Err(Error::MisMatchType),
+                };
+                Ok(number * unit)
+            }
+            _ => Err(Error::MisMatchType),
+        }
+    }
+
+    /// Duration, seconds as a number or a string with unit(`500ms`, `30s`, `5m`, `1h`, `1d`)
+    pub fn duration(&self) -> Result<Duration, Error> {
+        match self {
+            Value::Number(x) if *x >= 0.0 => Ok(Duration::from_secs_f64(*x)),
+            Value::String(x) => {
+                let split = x.find(|c: char| !c.is_ascii_digit()).unwrap_or(x.len());
+                let (number, unit) = x.split_at(split);
+                let number: u64 = number.parse().map_err(|_| Error::MisMatchType)?;
+                match unit.trim().to_ascii_lowercase().as_str() {
+                    "ms" => Ok(Duration::from_millis(number)),
+                    "" | "s" => Ok(Duration::from_secs(number)),
+                    "m" => Ok(Duration::from_secs(number * 60)),
+                    "h" => Ok(Duration::from_secs(number * 3600)),
+                    "d" => Ok(Duration::from_secs(number * 86400)),
+                    _ => Err(Error::MisMatchType),
+                }
+            }
+            _ => Err(Error::MisMatchType),
+        }
+    }
+}
+
 impl From<String> for Value {
     fn from(value: String) -> Self {
         let value = value.trim().to_string();
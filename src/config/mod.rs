@@ -1,9 +1,16 @@
+mod action;
 mod config;
 mod level;
+mod network;
 mod parser;
+mod rewrite;
+mod rule;
+mod table;
+mod template;
 mod tree;
 
 pub mod prelude {
-    pub use super::config::hash;
-    pub use super::config::AppState;
+    pub use super::action::Action;
+    pub use super::config::{AppState, Proxy, Route};
+    pub use super::template::Captures;
 }
@@ -0,0 +1,63 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Block of addresses in CIDR notation(`10.0.0.0/8`, `fd00::/8`), a plain address is a single host
+#[derive(Debug, Clone, PartialEq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Network {
+    type Err = ();
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match input.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (input.trim(), None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| ())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(x) => x.parse().map_err(|_| ())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(());
+        }
+        Ok(Network { addr, prefix })
+    }
+}
+
+impl Network {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener come as `::ffff:a.b.c.d`
+        let (network, addr, bits) = match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(x), IpAddr::V4(y)) => (u32::from(x) as u128, u32::from(y) as u128, 32),
+            (IpAddr::V6(x), IpAddr::V6(y)) => (u128::from(x), u128::from(y), 128),
+            _ => return false,
+        };
+        let shift = bits - self.prefix as u32;
+        shift >= bits || network >> shift == addr >> shift
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cidr() {
+        let network: Network = "10.1.0.0/16".parse().unwrap();
+        assert!(network.contains("10.1.2.3".parse().unwrap()));
+        assert!(network.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!network.contains("10.2.0.1".parse().unwrap()));
+
+        let host: Network = "::1".parse().unwrap();
+        assert!(host.contains("::1".parse().unwrap()));
+        assert!(!host.contains("127.0.0.1".parse().unwrap()));
+
+        let any: Network = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("192.0.2.1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+    }
+}
@@ -93,7 +93,23 @@ impl Parser {
                 return Level::Level(value.to_string(), children);
             }
             if value.starts_with("-") {
-                return Level::List(value.strip_prefix("-").unwrap().into());
+                let item = value.strip_prefix("-").unwrap();
+                let children = node.children(tree);
+                if children.is_empty() {
+                    return Level::List(item.into());
+                }
+                // `- addr: ...` with more fields below is an entry of fields
+                let (name, field) = item.split_once(":").unwrap();
+                let mut fields = vec![Level::Level(
+                    name.trim().to_string(),
+                    vec![Level::Unspecified(field.into())],
+                )];
+                fields.extend(
+                    children
+                        .into_iter()
+                        .map(|node| recursive_parsing(node, tree)),
+                );
+                return Level::Level("-".to_string(), fields);
             }
             if value.ends_with("]") {
                 let (value, field) = value.split_once(":").unwrap();
@@ -117,6 +133,8 @@ impl Parser {
 // Level,        routing:
 // Array,        routing: ["a.example.com"]
 // List,         - a.example.com
+// Entry,        - addr: a.example.com
+//                 weight: 3
 // Bool,         rewrite: true
 // Number,       weight: 1
 // Unspecified,  value: "ABC"
@@ -142,6 +160,36 @@ mod test {
         assert_eq!(vec!["127.0.0.1:8000", "b.example.com:8001",], lists)
     }
 
+    #[test]
+    fn entries() {
+        let file = fs::File::open("test/routesyml").unwrap();
+        let reader = io::BufReader::new(file);
+        let parser = Parser::new(reader);
+        let root = parser.parse();
+
+        let routing = root
+            .level(vec![
+                "hosts",
+                "a.example.com",
+                "routes",
+                "upload",
+                "routing",
+            ])
+            .unwrap();
+        let entries = match routing {
+            Level::Level(_, x) => x,
+            _ => panic!("routing is not a level"),
+        };
+        assert!(matches!(&entries[0], Level::List(_)));
+        let addr: String = entries[1].value(vec!["addr"]).unwrap().try_into().unwrap();
+        let weight: i64 = entries[1]
+            .value(vec!["weight"])
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!((addr.as_str(), weight), ("127.0.0.1:8007", 3));
+    }
+
     #[test]
     fn value_f64() {
         let file = fs::File::open("test/simpleyml").unwrap();
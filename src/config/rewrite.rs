@@ -0,0 +1,165 @@
+use regex::bytes::Regex;
+
+use super::level;
+use super::template::Captures;
+
+/// Rules rewriting the request target before it is sent to upstream
+///
+/// Applied in order: `set-path`, regex rules, `strip-prefix`, `add-prefix`
+#[derive(Debug, Default)]
+pub struct Rewrite {
+    set_path: Option<String>,
+    strip_prefix: Option<Vec<u8>>,
+    add_prefix: Option<Vec<u8>>,
+    rules: Vec<(Regex, Vec<u8>)>,
+}
+
+impl Rewrite {
+    pub fn is_empty(&self) -> bool {
+        self.set_path.is_none()
+            && self.strip_prefix.is_none()
+            && self.add_prefix.is_none()
+            && self.rules.is_empty()
+    }
+    /// Rewrite the origin-form target(`path?query`)
+    ///
+    /// `set-path` may refer to captures of host and path matchers
+    pub fn apply(&self, target: &[u8], captures: &Captures) -> Vec<u8> {
+        let mut target = match &self.set_path {
+            Some(template) => captures.expand(template).into_bytes(),
+            None => target.to_vec(),
+        };
+        for (pattern, replacement) in &self.rules {
+            target = pattern
+                .replace(&target, replacement.as_slice())
+                .into_owned();
+        }
+
+        let split = target
+            .iter()
+            .position(|&x| x == b'?')
+            .unwrap_or(target.len());
+        let (path, query) = target.split_at(split);
+        let mut path = path.to_vec();
+
+        if let Some(prefix) = &self.strip_prefix {
+            if path.starts_with(prefix) && matches!(path.get(prefix.len()), None | Some(b'/')) {
+                path.drain(0..prefix.len());
+            }
+        }
+        if !path.starts_with(b"/") {
+            path.insert(0, b'/');
+        }
+        if let Some(prefix) = &self.add_prefix {
+            let prefix = prefix.strip_suffix(b"/").unwrap_or(prefix);
+            path = [prefix, &path].concat();
+        }
+
+        [path.as_slice(), query].concat()
+    }
+}
+
+impl TryFrom<&level::Level> for Rewrite {
+    type Error = level::Error;
+
+    fn try_from(level: &level::Level) -> Result<Self, Self::Error> {
+        let prefix = |name: &str| -> Result<Option<Vec<u8>>, level::Error> {
+            match level.value(vec![name]) {
+                Ok(x) => {
+                    let prefix: String = x.try_into()?;
+                    Ok(Some(prefix.into_bytes()))
+                }
+                Err(_) => Ok(None),
+            }
+        };
+
+        let rules = level
+            .list(vec!["rewrite"])
+            .unwrap_or_default()
+            .into_iter()
+            .map(|rule| {
+                let rule: String = rule.try_into()?;
+                let (pattern, replacement) = rule
+                    .split_once(' ')
+                    .ok_or(level::Error::MisMatchStructure)?;
+                let pattern = Regex::new(pattern)
+                    .unwrap_or_else(|_| panic!("fail parsing regex {:?}", pattern));
+                Ok((pattern, replacement.trim().as_bytes().to_vec()))
+            })
+            .collect::<Result<Vec<_>, level::Error>>()?;
+
+        let set_path = match level.value(vec!["set-path"]) {
+            Ok(x) => Some(x.try_into()?),
+            Err(_) => None,
+        };
+
+        Ok(Rewrite {
+            set_path,
+            strip_prefix: prefix("strip-prefix")?,
+            add_prefix: prefix("add-prefix")?,
+            rules,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn prefix() {
+        let rewrite = Rewrite {
+            set_path: None,
+            strip_prefix: Some(b"/api/v1".to_vec()),
+            add_prefix: Some(b"/backend/".to_vec()),
+            rules: vec![],
+        };
+        assert_eq!(
+            rewrite.apply(b"/api/v1/users?id=1", &Captures::new()),
+            b"/backend/users?id=1"
+        );
+        assert_eq!(rewrite.apply(b"/api/v1", &Captures::new()), b"/backend/");
+        assert_eq!(
+            rewrite.apply(b"/api/v10", &Captures::new()),
+            b"/backend/api/v10"
+        );
+    }
+
+    #[test]
+    fn regex() {
+        let rewrite = Rewrite {
+            set_path: None,
+            strip_prefix: None,
+            add_prefix: None,
+            rules: vec![(
+                Regex::new(r"^/u/(\d+)/avatar$").unwrap(),
+                b"/avatar?user=$1".to_vec(),
+            )],
+        };
+        assert_eq!(
+            rewrite.apply(b"/u/42/avatar", &Captures::new()),
+            b"/avatar?user=42"
+        );
+        assert_eq!(
+            rewrite.apply(b"/u/me/avatar", &Captures::new()),
+            b"/u/me/avatar"
+        );
+    }
+
+    #[test]
+    fn set_path() {
+        let rewrite = Rewrite {
+            set_path: Some("/avatar?user=$1&tenant=$tenant".to_string()),
+            strip_prefix: None,
+            add_prefix: None,
+            rules: vec![],
+        };
+        let mut captures = Captures::new();
+        captures.set("1", "42".to_string());
+        captures.set("tenant", "abc".to_string());
+        assert_eq!(
+            rewrite.apply(b"/u/42/avatar", &captures),
+            b"/avatar?user=42&tenant=abc"
+        );
+    }
+}
@@ -0,0 +1,291 @@
+use regex::bytes::Regex;
+
+use super::level;
+use super::template::Captures;
+use crate::http::prelude::header::Fields;
+use crate::http::prelude::startline::{Method, StartLine};
+
+/// Match on the path of request target(without query)
+///
+/// `path: /api` is a prefix, `path: ^/u/(\d+)$` is a regex.
+#[derive(Debug)]
+pub enum PathMatcher {
+    Any,
+    Prefix(Vec<u8>),
+    Regex(Regex),
+}
+
+impl PathMatcher {
+    pub fn matches(&self, path: &[u8], captures: &mut Captures) -> bool {
+        match self {
+            PathMatcher::Any => true,
+            // on a segment boundary, `/api` is no prefix of `/apiary`
+            PathMatcher::Prefix(prefix) => {
+                path.starts_with(prefix)
+                    && (prefix.ends_with(b"/")
+                        || matches!(path.get(prefix.len()), None | Some(b'/')))
+            }
+            PathMatcher::Regex(pattern) => match pattern.captures(path) {
+                Some(x) => {
+                    captures.extend(pattern, &x, "");
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+}
+
+impl From<&str> for PathMatcher {
+    fn from(path: &str) -> Self {
+        if path.starts_with('^') {
+            let pattern =
+                Regex::new(path).unwrap_or_else(|_| panic!("fail parsing regex {:?}", path));
+            PathMatcher::Regex(pattern)
+        } else {
+            PathMatcher::Prefix(path.as_bytes().to_vec())
+        }
+    }
+}
+
+/// Match on the value of header, cookie or query parameter
+///
+/// Exact match, or regex when starting with `^`
+#[derive(Debug)]
+pub enum ValueMatcher {
+    Exact(Vec<u8>),
+    Regex(Regex),
+}
+
+impl ValueMatcher {
+    pub fn matches(&self, value: &[u8], captures: &mut Captures) -> bool {
+        match self {
+            ValueMatcher::Exact(x) => x == value,
+            ValueMatcher::Regex(pattern) => match pattern.captures(value) {
+                Some(x) => {
+                    captures.extend(pattern, &x, "");
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+}
+
+impl From<&str> for ValueMatcher {
+    fn from(value: &str) -> Self {
+        if value.starts_with('^') {
+            let pattern =
+                Regex::new(value).unwrap_or_else(|_| panic!("fail parsing regex {:?}", value));
+            ValueMatcher::Regex(pattern)
+        } else {
+            ValueMatcher::Exact(value.as_bytes().to_vec())
+        }
+    }
+}
+
+/// Condition on the parsed request, a missing value never matches
+///
+/// Without value matcher, the presence of the field is enough.
+#[derive(Debug)]
+pub enum Condition {
+    Method(Vec<Method>),
+    Header(String, Option<ValueMatcher>),
+    Cookie(String, Option<ValueMatcher>),
+    Query(String, Option<ValueMatcher>),
+    Insecure,
+}
+
+impl Condition {
+    pub fn matches(&self, startline: &StartLine, fields: &Fields, captures: &mut Captures) -> bool {
+        let (value, matcher) = match self {
+            Condition::Method(methods) => return methods.contains(&startline.method),
+            Condition::Insecure => return !fields.is_secure(),
+            Condition::Header(name, matcher) => (fields.get(name), matcher),
+            Condition::Cookie(name, matcher) => (fields.cookie(name), matcher),
+            Condition::Query(name, matcher) => (query(startline, name), matcher),
+        };
+        match (value, matcher) {
+            (Some(value), Some(matcher)) => matcher.matches(value, captures),
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+}
+
+/// Value of a query parameter, compared without percent-decoding
+fn query<'a>(startline: &'a StartLine, name: &str) -> Option<&'a [u8]> {
+    let (_, origin) = startline.split_target();
+    let split = origin.iter().position(|&x| x == b'?')?;
+    origin[split + 1..].split(|&x| x == b'&').find_map(|pair| {
+        let split = pair.iter().position(|&x| x == b'=').unwrap_or(pair.len());
+        if &pair[..split] == name.as_bytes() {
+            Some(pair.get(split + 1..).unwrap_or_default())
+        } else {
+            None
+        }
+    })
+}
+
+/// Conditions a request has to fulfill to take a route, all of them have to match
+#[derive(Debug)]
+pub struct Matcher {
+    path: PathMatcher,
+    conditions: Vec<Condition>,
+}
+
+impl Matcher {
+    /// Match requests not coming over https
+    pub fn insecure() -> Self {
+        Matcher {
+            path: PathMatcher::Any,
+            conditions: vec![Condition::Insecure],
+        }
+    }
+    pub fn matches(&self, startline: &StartLine, fields: &Fields, captures: &mut Captures) -> bool {
+        let (_, origin) = startline.split_target();
+        let path = match origin.iter().position(|&x| x == b'?') {
+            Some(x) => &origin[..x],
+            None => origin,
+        };
+        self.path.matches(path, captures)
+            && self
+                .conditions
+                .iter()
+                .all(|x| x.matches(startline, fields, captures))
+    }
+}
+
+/// Parse a list of `name: value` or `name`
+fn pairs(
+    level: &level::Level,
+    name: &str,
+) -> Result<Vec<(String, Option<ValueMatcher>)>, level::Error> {
+    level
+        .list(vec![name])
+        .unwrap_or_default()
+        .into_iter()
+        .map(|pair| {
+            let pair: String = pair.try_into()?;
+            Ok(match pair.split_once(':') {
+                Some((name, value)) => (name.trim().to_string(), Some(value.trim().into())),
+                None => (pair.trim().to_string(), None),
+            })
+        })
+        .collect()
+}
+
+impl TryFrom<&level::Level> for Matcher {
+    type Error = level::Error;
+
+    fn try_from(level: &level::Level) -> Result<Self, Self::Error> {
+        let path = match level.value(vec!["path"]) {
+            Ok(x) => {
+                let path: String = x.try_into()?;
+                path.as_str().into()
+            }
+            Err(_) => PathMatcher::Any,
+        };
+
+        let mut conditions = vec![];
+        let methods = level
+            .list(vec!["method"])
+            .unwrap_or_default()
+            .into_iter()
+            .map(|x| {
+                let method: String = x.try_into()?;
+                method
+                    .to_ascii_uppercase()
+                    .parse()
+                    .map_err(|_| level::Error::MisMatchType)
+            })
+            .collect::<Result<Vec<Method>, level::Error>>()?;
+        if !methods.is_empty() {
+            conditions.push(Condition::Method(methods));
+        }
+        for (name, value) in pairs(level, "header")? {
+            conditions.push(Condition::Header(name, value));
+        }
+        for (name, value) in pairs(level, "cookie")? {
+            conditions.push(Condition::Cookie(name, value));
+        }
+        for (name, value) in pairs(level, "query")? {
+            conditions.push(Condition::Query(name, value));
+        }
+
+        Ok(Matcher { path, conditions })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn path_regex() {
+        let matcher = Matcher {
+            path: r"^/u/(\d+)/avatar$".into(),
+            conditions: vec![],
+        };
+        let startline: StartLine = b"GET /u/42/avatar?size=2 HTTP/1.1"
+            .as_ref()
+            .try_into()
+            .unwrap();
+        let mut captures = Captures::new();
+        assert!(matcher.matches(&startline, &Fields::new(), &mut captures));
+        assert_eq!(captures.get("1"), Some("42"));
+
+        let startline: StartLine = b"GET /u/42 HTTP/1.1".as_ref().try_into().unwrap();
+        assert!(!matcher.matches(&startline, &Fields::new(), &mut Captures::new()));
+    }
+
+    #[test]
+    fn path_prefix() {
+        let matcher = Matcher {
+            path: "/api".into(),
+            conditions: vec![],
+        };
+        let startline: StartLine = b"GET /api/users HTTP/1.1".as_ref().try_into().unwrap();
+        assert!(matcher.matches(&startline, &Fields::new(), &mut Captures::new()));
+        let startline: StartLine = b"GET /api?page=2 HTTP/1.1".as_ref().try_into().unwrap();
+        assert!(matcher.matches(&startline, &Fields::new(), &mut Captures::new()));
+        let startline: StartLine = b"GET /apiary HTTP/1.1".as_ref().try_into().unwrap();
+        assert!(!matcher.matches(&startline, &Fields::new(), &mut Captures::new()));
+    }
+
+    #[test]
+    fn conditions() {
+        let matcher = Matcher {
+            path: "/api".into(),
+            conditions: vec![
+                Condition::Method(vec![Method::GET, Method::POST]),
+                Condition::Header("X-Api-Version".to_string(), Some("2".into())),
+                Condition::Cookie("beta".to_string(), None),
+                Condition::Query("lang".to_string(), Some("^(en|fr)$".into())),
+            ],
+        };
+        let mut fields = Fields::new();
+        fields.push(b"x-api-version: 2");
+        fields.push(b"Cookie: beta=1");
+
+        let startline: StartLine = b"GET /api/users?lang=fr HTTP/1.1"
+            .as_ref()
+            .try_into()
+            .unwrap();
+        let mut captures = Captures::new();
+        assert!(matcher.matches(&startline, &fields, &mut captures));
+        assert_eq!(captures.get("1"), Some("fr"));
+
+        let startline: StartLine = b"DELETE /api/users?lang=fr HTTP/1.1"
+            .as_ref()
+            .try_into()
+            .unwrap();
+        assert!(!matcher.matches(&startline, &fields, &mut Captures::new()));
+
+        let startline: StartLine = b"GET /api/users?lang=de HTTP/1.1"
+            .as_ref()
+            .try_into()
+            .unwrap();
+        assert!(!matcher.matches(&startline, &fields, &mut Captures::new()));
+    }
+}
@@ -0,0 +1,119 @@
+use regex::bytes::Regex;
+use std::collections::HashMap;
+
+use super::template::Captures;
+
+const DEFAULT_HOST: &str = "default";
+const WILDCARD_PREFIX: &str = "*.";
+const REGEX_PREFIX: &str = "^";
+
+/// Host name lookup table
+///
+/// Searched in most-specific-first order: exact name, longest wildcard
+/// suffix(`*.example.com`), regex(`^tenant-(\w+)\.example\.com$`) in
+/// config order, then the `default` entry.
+#[derive(Debug)]
+pub struct HostTable<T> {
+    exact: HashMap<String, T>,
+    // sorted by suffix length, longest first
+    wildcard: Vec<(String, T)>,
+    pattern: Vec<(Regex, T)>,
+    default: Option<T>,
+}
+
+impl<T> HostTable<T> {
+    pub fn new() -> Self {
+        HostTable {
+            exact: HashMap::new(),
+            wildcard: vec![],
+            pattern: vec![],
+            default: None,
+        }
+    }
+    pub fn insert(&mut self, name: &str, value: T) {
+        if name == DEFAULT_HOST {
+            self.default = Some(value);
+        } else if let Some(suffix) = name.strip_prefix(WILDCARD_PREFIX) {
+            // keep the dot, so `*.example.com` doesn't match `badexample.com`
+            let suffix = format!(".{}", suffix);
+            let index = self
+                .wildcard
+                .iter()
+                .position(|(x, _)| x.len() < suffix.len())
+                .unwrap_or(self.wildcard.len());
+            self.wildcard.insert(index, (suffix, value));
+        } else if name.starts_with(REGEX_PREFIX) {
+            let pattern =
+                Regex::new(name).unwrap_or_else(|_| panic!("fail parsing regex {:?}", name));
+            self.pattern.push((pattern, value));
+        } else {
+            self.exact.insert(name.to_string(), value);
+        }
+    }
+    /// Every value, in no particular order
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.exact
+            .values()
+            .chain(self.wildcard.iter().map(|(_, x)| x))
+            .chain(self.pattern.iter().map(|(_, x)| x))
+            .chain(self.default.iter())
+    }
+    pub fn get(&self, host: &str) -> Option<(&T, Captures)> {
+        let mut captures = Captures::new();
+        if let Some(value) = self.exact.get(host) {
+            return Some((value, captures));
+        }
+        for (suffix, value) in &self.wildcard {
+            if host.len() > suffix.len() && host.ends_with(suffix.as_str()) {
+                return Some((value, captures));
+            }
+        }
+        for (pattern, value) in &self.pattern {
+            if let Some(x) = pattern.captures(host.as_bytes()) {
+                captures.extend(pattern, &x, "host");
+                return Some((value, captures));
+            }
+        }
+        self.default.as_ref().map(|x| (x, captures))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn most_specific_first() {
+        let mut table = HostTable::new();
+        table.insert("default", 0);
+        table.insert("*.example.com", 1);
+        table.insert("*.a.example.com", 2);
+        table.insert("a.example.com", 3);
+        table.insert(r"^tenant-(\w+)\.example\.org$", 4);
+
+        let get = |host| table.get(host).map(|x| *x.0);
+        assert_eq!(get("a.example.com"), Some(3));
+        assert_eq!(get("b.a.example.com"), Some(2));
+        assert_eq!(get("b.example.com"), Some(1));
+        assert_eq!(get("tenant-abc.example.org"), Some(4));
+        assert_eq!(get("example.com"), Some(0));
+        assert_eq!(get("badexample.com"), Some(0));
+    }
+
+    #[test]
+    fn without_default() {
+        let mut table = HostTable::new();
+        table.insert("*.example.com", 1);
+        assert_eq!(table.get("www.example.org"), None);
+    }
+
+    #[test]
+    fn regex_captures() {
+        let mut table = HostTable::new();
+        table.insert(r"^tenant-(?P<tenant>\w+)\.example\.com$", 1);
+        let (_, captures) = table.get("tenant-abc.example.com").unwrap();
+        assert_eq!(captures.get("tenant"), Some("abc"));
+        assert_eq!(captures.get("host1"), Some("abc"));
+        assert_eq!(captures.get("1"), None);
+    }
+}
@@ -0,0 +1,118 @@
+/// Variables captured while matching a request
+///
+/// Numbered groups are stored under their number(`"1"`), or `"host1"` for the host
+/// pattern so that path groups do not overwrite them, named groups under their name.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Captures {
+    variables: Vec<(String, String)>,
+}
+
+impl Captures {
+    pub fn new() -> Self {
+        Captures { variables: vec![] }
+    }
+    /// Set a variable, replacing the previous value of the same name
+    pub fn set(&mut self, name: &str, value: String) {
+        match self.variables.iter_mut().find(|(x, _)| x == name) {
+            Some((_, x)) => *x = value,
+            None => self.variables.push((name.to_string(), value)),
+        }
+    }
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.variables
+            .iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, x)| x.as_str())
+    }
+    /// Collect groups of a regex match, numbered ones named `prefix` and their number
+    pub fn extend(
+        &mut self,
+        pattern: &regex::bytes::Regex,
+        captures: &regex::bytes::Captures,
+        prefix: &str,
+    ) {
+        let names = pattern.capture_names().enumerate().skip(1);
+        for (index, name) in names {
+            let value = captures
+                .get(index)
+                .map(|x| String::from_utf8_lossy(x.as_bytes()).into_owned())
+                .unwrap_or_default();
+            if let Some(name) = name {
+                self.set(name, value.clone());
+            }
+            self.set(&format!("{}{}", prefix, index), value);
+        }
+    }
+    /// Substitute `$name`, `${name}` and `$1` in template, `$$` for a literal `$`
+    ///
+    /// Unknown variables are replaced by an empty string.
+    pub fn expand(&self, template: &str) -> String {
+        let mut output = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(index) = rest.find('$') {
+            output.push_str(&rest[..index]);
+            rest = &rest[index + 1..];
+
+            if let Some(x) = rest.strip_prefix('$') {
+                output.push('$');
+                rest = x;
+                continue;
+            }
+            let (name, remain) = match rest.strip_prefix('{') {
+                Some(x) => match x.find('}') {
+                    Some(end) => (&x[..end], &x[end + 1..]),
+                    None => {
+                        output.push('$');
+                        continue;
+                    }
+                },
+                None => {
+                    let end = rest
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                        .unwrap_or(rest.len());
+                    (&rest[..end], &rest[end..])
+                }
+            };
+            if name.is_empty() {
+                output.push('$');
+                continue;
+            }
+            output.push_str(self.get(name).unwrap_or_default());
+            rest = remain;
+        }
+        output.push_str(rest);
+        output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn expand() {
+        let mut captures = Captures::new();
+        let pattern = regex::bytes::Regex::new(r"^tenant-(?P<tenant>\w+)\.example\.com$").unwrap();
+        captures.extend(
+            &pattern,
+            &pattern.captures(b"tenant-abc.example.com").unwrap(),
+            "",
+        );
+
+        assert_eq!(captures.expand("/t/$tenant/${1}x"), "/t/abc/abcx");
+        assert_eq!(captures.expand("$$1 $unknown."), "$1 .");
+        assert_eq!(captures.expand("100$"), "100$");
+    }
+
+    #[test]
+    fn host_and_path() {
+        let mut captures = Captures::new();
+        let host = regex::bytes::Regex::new(r"^(\w+)\.example\.com$").unwrap();
+        captures.extend(&host, &host.captures(b"abc.example.com").unwrap(), "host");
+        let path = regex::bytes::Regex::new(r"^/u/(\d+)$").unwrap();
+        captures.extend(&path, &path.captures(b"/u/42").unwrap(), "");
+
+        assert_eq!(captures.expand("/$host1/$1"), "/abc/42");
+        assert_eq!(captures.expand("${host1}x${1}"), "abcx42");
+    }
+}
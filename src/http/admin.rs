@@ -0,0 +1,87 @@
+use std::net;
+
+use super::cache::Purge;
+use super::file::decode;
+use super::request::{Error, Request};
+use super::response::Response;
+use super::startline::Method;
+use crate::config::prelude::AppState;
+
+/// Targets in the query of `/purge`, None when malformed
+///
+/// `url=a.example.com/index.html`, `prefix=a.example.com/assets/` or `key=<surrogate key>`,
+/// repeated for more than one.
+fn purges(query: &[u8]) -> Option<Vec<Purge>> {
+    query
+        .split(|&x| x == b'&')
+        .filter(|x| !x.is_empty())
+        .map(|pair| {
+            let split = pair.iter().position(|&x| x == b'=')?;
+            let value = String::from_utf8(decode(&pair[split + 1..])?).ok()?;
+            match &pair[..split] {
+                b"url" => Some(Purge::Url(value)),
+                b"prefix" => Some(Purge::Prefix(value)),
+                b"key" => Some(Purge::Surrogate(value)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Answer a request to the admin listener
+///
+/// `POST /purge` invalidates cached responses of every host.
+pub async fn handle(state: &AppState, stream: &net::TcpStream) -> Result<(), Error> {
+    let request = Request::new(stream)?.parse().await?;
+    let startline = request.startline();
+    let (_, origin) = startline.split_target();
+    let (path, query) = match origin.iter().position(|&x| x == b'?') {
+        Some(x) => (&origin[..x], &origin[x + 1..]),
+        None => (origin, &origin[origin.len()..]),
+    };
+
+    let response = if path != b"/purge" {
+        Response::new(404)
+    } else if startline.method != Method::POST {
+        Response::new(405).header("Allow", "POST")
+    } else {
+        match purges(query) {
+            Some(purges) if !purges.is_empty() => {
+                let count: usize = state
+                    .caches()
+                    .iter()
+                    .flat_map(|cache| purges.iter().map(|x| cache.purge(x)))
+                    .sum();
+                println!("purged {} cached responses for {:?}", count, purges);
+                Response::new(200).body(format!("purged {}\n", count).into_bytes())
+            }
+            _ => Response::new(400),
+        }
+    };
+    response
+        .send(stream)
+        .await
+        .map_err(|_| Error::ClientIncompatible)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn purge_query() {
+        assert_eq!(
+            purges(b"url=a.example.com%2Fa%3Fb%3Dc&key=post-1"),
+            Some(vec![
+                Purge::Url("a.example.com/a?b=c".to_string()),
+                Purge::Surrogate("post-1".to_string()),
+            ])
+        );
+        assert_eq!(
+            purges(b"prefix=a.example.com/assets/").map(|x| x.len()),
+            Some(1)
+        );
+        assert_eq!(purges(b"path=/a"), None);
+        assert_eq!(purges(b""), Some(vec![]));
+    }
+}
@@ -0,0 +1,176 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// name of the next temp file
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Limits of holding a whole message body before passing it on
+///
+/// Up to `memory` bytes are kept in memory, beyond it the body goes to a temp file.
+/// Bodies larger than `max` are refused.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Buffer {
+    pub memory: u64,
+    pub max: u64,
+}
+
+impl Default for Buffer {
+    fn default() -> Self {
+        Buffer {
+            memory: 1 << 20,
+            max: 64 << 20,
+        }
+    }
+}
+
+/// Message body held by the proxy, which can be read again as many times as needed
+///
+/// The temp file is removed when dropped.
+pub struct Buffered {
+    limit: Buffer,
+    memory: Vec<u8>,
+    file: Option<(fs::File, PathBuf)>,
+    length: u64,
+}
+
+impl Buffered {
+    pub fn new(limit: &Buffer) -> Self {
+        Buffered {
+            limit: limit.clone(),
+            memory: vec![],
+            file: None,
+            length: 0,
+        }
+    }
+
+    /// Body already at hand, kept in memory whatever the size
+    pub fn memory(body: Vec<u8>) -> Self {
+        let length = body.len() as u64;
+        Buffered {
+            limit: Buffer {
+                memory: length,
+                max: length,
+            },
+            memory: body,
+            file: None,
+            length,
+        }
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// Body when it is held in memory, empty when it went to the temp file
+    pub fn in_memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// The temp file read from the start, None when the body is in memory
+    pub fn file(&self) -> Result<Option<fs::File>, io::Error> {
+        match &self.file {
+            Some((_, path)) => Ok(Some(fs::File::open(path)?)),
+            None => Ok(None),
+        }
+    }
+
+    // move what is in memory to a new temp file
+    fn spill(&mut self) -> Result<(), io::Error> {
+        let name = format!(
+            "crate-{}-{}.body",
+            process::id(),
+            SEQUENCE.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = std::env::temp_dir().join(name);
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // keep the path first, so the file is removed even if the write fails
+        self.file = Some((file.try_clone()?, path));
+        file.write_all(&self.memory)?;
+        self.memory = vec![];
+        Ok(())
+    }
+
+    /// Write `head` and the body, a body in the temp file is sent with sendfile
+    pub fn send<W>(&self, head: &[u8], mut writer: W) -> Result<(), io::Error>
+    where
+        W: io::Write,
+    {
+        match self.file()? {
+            None => writer.write_all(&[head, &self.memory].concat()),
+            Some(mut file) => {
+                writer.write_all(head)?;
+                io::copy(&mut file, &mut writer)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl io::Write for Buffered {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.length + buf.len() as u64 > self.limit.max {
+            return Err(io::ErrorKind::FileTooLarge.into());
+        }
+        if self.file.is_none() && self.length + buf.len() as u64 > self.limit.memory {
+            self.spill()?;
+        }
+        match &mut self.file {
+            Some((file, _)) => file.write_all(buf)?,
+            None => self.memory.extend_from_slice(buf),
+        }
+        self.length += buf.len() as u64;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some((file, _)) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Buffered {
+    fn drop(&mut self) {
+        if let Some((_, path)) = &self.file {
+            fs::remove_file(path).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn spilling() {
+        let limit = Buffer { memory: 4, max: 8 };
+        let mut body = Buffered::new(&limit);
+        body.write_all(b"abc").unwrap();
+        assert!(body.file.is_none());
+        body.write_all(b"def").unwrap();
+        let path = body.file.as_ref().unwrap().1.clone();
+        assert!(path.exists());
+        assert_eq!(
+            body.write_all(b"ghi").unwrap_err().kind(),
+            io::ErrorKind::FileTooLarge
+        );
+
+        // sent as many times as asked
+        for _ in 0..2 {
+            let mut output = vec![];
+            body.send(b"head ", &mut output).unwrap();
+            assert_eq!(output, b"head abcdef");
+        }
+        assert_eq!(body.length(), 6);
+        assert!(body.in_memory().is_empty());
+        drop(body);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,508 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{variant, vary, Content, Entry, Purge, Store};
+use crate::http::header::Fields;
+use crate::http::upstream::Head;
+
+const INDEX: &str = "index";
+// records appended before the index is written anew, at least
+const COMPACT_MIN: usize = 64;
+
+fn hex(input: &[u8]) -> String {
+    input.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn unhex(input: &str) -> Option<Vec<u8>> {
+    if !input.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..input.len())
+        .step_by(2)
+        .map(|x| u8::from_str_radix(input.get(x..x + 2)?, 16).ok())
+        .collect()
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// name of the files of an entry in the cache directory
+fn file_name(entry: &Entry) -> Option<&str> {
+    match &entry.content {
+        Content::File(x, _) => x.file_stem()?.to_str(),
+        Content::Memory(_) => None,
+    }
+}
+
+/// Body of a response being written into the cache directory
+///
+/// The file is removed when dropped before being stored.
+pub struct Spool {
+    file: fs::File,
+    path: Option<PathBuf>,
+    name: String,
+    length: u64,
+}
+
+impl Spool {
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+    /// Read what was written from the start, still readable after the spool is gone
+    pub fn reader(&mut self) -> Result<fs::File, io::Error> {
+        self.file.flush()?;
+        match &self.path {
+            Some(x) => fs::File::open(x),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+}
+
+impl io::Write for Spool {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let byte_written = self.file.write(buf)?;
+        self.length += byte_written as u64;
+        Ok(byte_written)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            fs::remove_file(path).ok();
+        }
+    }
+}
+
+struct State {
+    store: Store,
+    // name of the next file
+    sequence: u64,
+}
+
+struct Index {
+    // opened for appending, None until the index is first written
+    file: Option<fs::File>,
+    // records appended since the index was last written anew
+    appended: usize,
+}
+
+/// Cache tier in a directory, surviving restarts with an index file
+///
+/// Each entry is a `<name>.head` and a `<name>.body` file. `index` is a log of
+/// entries stored and removed(`- <name>`), written anew with the entries left
+/// in least recently used order once it holds more records than entries.
+pub struct Disk {
+    dir: PathBuf,
+    capacity: u64,
+    max_object: u64,
+    state: Mutex<State>,
+    // taken without `state`, lookups never wait on writing the index
+    index: Mutex<Index>,
+}
+
+impl std::fmt::Debug for Disk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Disk")
+            .field("dir", &self.dir)
+            .field("capacity", &self.capacity)
+            .field("max_object", &self.max_object)
+            .finish()
+    }
+}
+
+impl Disk {
+    /// Open the cache directory, loading entries listed in its index
+    ///
+    /// Files not listed in the index are left from an interrupted write, and removed.
+    pub fn open(dir: &Path, capacity: u64, max_object: u64) -> Result<Disk, io::Error> {
+        fs::create_dir_all(dir)?;
+        let mut state = State {
+            store: Store::default(),
+            sequence: 0,
+        };
+        // key each name was stored at
+        let mut keys = HashMap::new();
+        let index = fs::read_to_string(dir.join(INDEX)).unwrap_or_default();
+        for line in index.lines() {
+            if let Some(name) = line.strip_prefix("- ") {
+                let key: &String = match keys.get(name) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                // the key may be stored again under another name since
+                let current = state.store.entries.get(key).and_then(|(x, _)| file_name(x));
+                if current == Some(name) {
+                    state.store.remove(key);
+                }
+            } else if let Some((name, key, vary, entry)) = Disk::restore(dir, line) {
+                let sequence = u64::from_str_radix(&name, 16).unwrap_or_default();
+                state.sequence = state.sequence.max(sequence + 1);
+                state
+                    .store
+                    .insert(key.clone(), vary, Arc::new(entry), u64::MAX);
+                keys.insert(name, key);
+            }
+        }
+        let names: HashSet<&str> = state
+            .store
+            .entries
+            .values()
+            .filter_map(|(x, _)| file_name(x))
+            .collect();
+
+        for file in fs::read_dir(dir)? {
+            let path = file?.path();
+            let stem = path
+                .file_stem()
+                .and_then(|x| x.to_str())
+                .unwrap_or_default();
+            if path.file_name() != Some(INDEX.as_ref()) && !names.contains(stem) {
+                fs::remove_file(&path).ok();
+            }
+        }
+
+        let disk = Disk {
+            dir: dir.to_path_buf(),
+            capacity,
+            max_object,
+            state: Mutex::new(state),
+            index: Mutex::new(Index {
+                file: None,
+                appended: 0,
+            }),
+        };
+        // the capacity may have been lowered since
+        let mut state = disk.state.lock().unwrap();
+        while state.store.size > capacity {
+            let key = match state.store.order.first_key_value() {
+                Some((_, x)) => x.clone(),
+                None => break,
+            };
+            if let Some(entry) = state.store.remove(&key) {
+                disk.delete(&entry, None);
+            }
+        }
+        drop(state);
+        disk.compact(&mut disk.index.lock().unwrap())?;
+        Ok(disk)
+    }
+
+    // index line: name, stored, age, lifetime, length, vary, key in hex
+    fn restore(dir: &Path, line: &str) -> Option<(String, String, Vec<String>, Entry)> {
+        let mut parts = line.split(' ');
+        let name = parts.next()?.to_string();
+        let mut number = || parts.next()?.parse::<u64>().ok();
+        let stored = UNIX_EPOCH + Duration::from_secs(number()?);
+        let age = Duration::from_secs(number()?);
+        let lifetime = Duration::from_secs(number()?);
+        let length = number()?;
+        let vary = match parts.next()? {
+            "-" => vec![],
+            x => x.split(',').map(|x| x.to_string()).collect(),
+        };
+        let key = String::from_utf8(unhex(parts.next()?)?).ok()?;
+
+        let head = Head::parse(&fs::read(dir.join(format!("{}.head", name))).ok()?)?;
+        let path = dir.join(format!("{}.body", name));
+        if fs::metadata(&path).ok()?.len() != length {
+            return None;
+        }
+        let entry = Entry {
+            head,
+            content: Content::File(path, length),
+            stored,
+            age,
+            lifetime,
+        };
+        Some((name, key, vary, entry))
+    }
+
+    // index line of an entry, see `restore`
+    fn record(store: &Store, key: &str, entry: &Entry) -> Option<String> {
+        let primary = key.split('\n').next().unwrap_or_default();
+        let vary = match store.variants.get(primary) {
+            Some((x, _)) if !x.is_empty() => x.join(","),
+            _ => "-".to_string(),
+        };
+        Some(format!(
+            "{} {} {} {} {} {} {}\n",
+            file_name(entry)?,
+            seconds(entry.stored),
+            entry.age.as_secs(),
+            entry.lifetime.as_secs(),
+            entry.content.length(),
+            vary,
+            hex(key.as_bytes())
+        ))
+    }
+
+    // write the index anew with the entries left, the state lock held only to list them
+    fn compact(&self, index: &mut Index) -> Result<(), io::Error> {
+        let records: String = {
+            let state = self.state.lock().unwrap();
+            let store = &state.store;
+            store
+                .order
+                .values()
+                .filter_map(|key| Disk::record(store, key, &store.entries[key].0))
+                .collect()
+        };
+        let temp = self.dir.join(format!("{}.tmp", INDEX));
+        fs::write(&temp, records)?;
+        fs::rename(temp, self.dir.join(INDEX))?;
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .open(self.dir.join(INDEX))?;
+        index.file = Some(file);
+        index.appended = 0;
+        Ok(())
+    }
+
+    // add records to the index, written anew once they outnumber the entries
+    fn append(&self, records: String) {
+        let mut index = self.index.lock().unwrap();
+        index.appended += records.lines().count();
+        let entries = self.state.lock().unwrap().store.entries.len();
+        let compact = index.appended > entries.max(COMPACT_MIN);
+        let result = match index.file.as_mut() {
+            Some(file) if !compact => file.write_all(records.as_bytes()),
+            _ => self.compact(&mut index),
+        };
+        if let Err(err) = result {
+            println!("fail writing cache index {:?}: {}", self.dir, err);
+        }
+    }
+
+    // remove files of an entry, unless still used by `kept`
+    fn delete(&self, entry: &Entry, kept: Option<&Path>) {
+        if let Content::File(path, _) = &entry.content {
+            if Some(path.as_path()) != kept {
+                fs::remove_file(path).ok();
+                fs::remove_file(path.with_extension("head")).ok();
+            }
+        }
+    }
+
+    pub fn max_object(&self) -> u64 {
+        self.max_object
+    }
+
+    pub fn get(&self, key: &str, fields: &Fields) -> Option<Arc<Entry>> {
+        self.state.lock().unwrap().store.get(key, fields)
+    }
+
+    /// Remove entries matching the purge with their files, returning how many
+    pub fn purge(&self, purge: &Purge) -> usize {
+        let removed = self.state.lock().unwrap().store.purge(purge);
+        let mut records = String::new();
+        for entry in &removed {
+            self.delete(entry, None);
+            if let Some(name) = file_name(entry) {
+                records.push_str(&format!("- {}\n", name));
+            }
+        }
+        if !records.is_empty() {
+            self.append(records);
+        }
+        removed.len()
+    }
+
+    pub fn spool(&self) -> Result<Spool, io::Error> {
+        let name = {
+            let mut state = self.state.lock().unwrap();
+            state.sequence += 1;
+            format!("{:016x}", state.sequence - 1)
+        };
+        let path = self.dir.join(format!("{}.tmp", name));
+        Ok(Spool {
+            file: fs::File::create(&path)?,
+            path: Some(path),
+            name,
+            length: 0,
+        })
+    }
+
+    pub fn insert_spool(
+        &self,
+        key: &str,
+        fields: &Fields,
+        head: Head,
+        mut spool: Spool,
+    ) -> Option<Arc<Entry>> {
+        if spool.length > self.max_object {
+            return None;
+        }
+        spool.file.flush().ok()?;
+        let temp = spool.path.as_ref()?;
+        let path = self.dir.join(format!("{}.body", spool.name));
+        fs::rename(temp, &path).ok()?;
+        spool.path = None;
+
+        let entry = self.insert(key, fields, head, Content::File(path.clone(), spool.length));
+        if entry.is_none() {
+            fs::remove_file(path).ok();
+        }
+        entry
+    }
+
+    /// Store a response with body already in the cache directory
+    pub fn insert(
+        &self,
+        key: &str,
+        fields: &Fields,
+        head: Head,
+        content: Content,
+    ) -> Option<Arc<Entry>> {
+        let path = match &content {
+            Content::File(x, _) => x.clone(),
+            Content::Memory(_) => return None,
+        };
+        let vary = vary(&head);
+        let entry = Arc::new(Entry::new(head, content)?);
+        if entry.size() > self.capacity {
+            return None;
+        }
+        // write the head aside first, the index never lists an entry with partial files
+        let temp = path.with_extension("head.tmp");
+        fs::write(&temp, entry.head.to_bytes()).ok()?;
+        fs::rename(&temp, path.with_extension("head")).ok()?;
+
+        let full = variant(key, &vary, fields);
+        let (removed, record) = {
+            let mut state = self.state.lock().unwrap();
+            let removed = state
+                .store
+                .insert(full.clone(), vary, entry.clone(), self.capacity);
+            (removed, Disk::record(&state.store, &full, &entry))
+        };
+        // files and index are written after the lock, a removal before the record
+        let mut records = String::new();
+        for removed in removed {
+            self.delete(&removed, Some(&path));
+            match file_name(&removed) {
+                Some(x) if Some(x) != file_name(&entry) => records.push_str(&format!("- {}\n", x)),
+                _ => {}
+            }
+        }
+        records.extend(record);
+        self.append(records);
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{Cache, Lookup};
+    use super::*;
+    use std::io::Read;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cache-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    fn head() -> Head {
+        Head::parse(b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\n\r\n").unwrap()
+    }
+
+    fn body(entry: &Entry) -> Vec<u8> {
+        let mut output = vec![];
+        if let Content::File(path, _) = &entry.content {
+            fs::File::open(path)
+                .unwrap()
+                .read_to_end(&mut output)
+                .unwrap();
+        }
+        output
+    }
+
+    #[test]
+    fn index_reload() {
+        let dir = dir("reload");
+        let fields = Fields::new();
+        let cache = Cache::new(1024, 4).disk(Disk::open(&dir, 4096, 1024).unwrap());
+        let mut spool = cache.spool().unwrap();
+        spool.write_all(b"hello world").unwrap();
+        let entry = cache.insert_spool("a", &fields, head(), spool).unwrap();
+        assert_eq!(body(&entry), b"hello world");
+
+        // files left by an interrupted write
+        let stray = cache.spool().unwrap();
+        std::mem::forget(stray);
+        drop(cache);
+
+        let cache = Cache::new(1024, 4).disk(Disk::open(&dir, 4096, 1024).unwrap());
+        match cache.lookup("a", &fields) {
+            Lookup::Fresh(x) => assert_eq!(body(&x), b"hello world"),
+            _ => panic!("entry should survive reopening"),
+        }
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn disk_eviction() {
+        let dir = dir("eviction");
+        let fields = Fields::new();
+        let disk = Disk::open(&dir, 300, 200).unwrap();
+        let mut inserted = vec![];
+        for key in ["a", "b", "c"] {
+            let mut spool = disk.spool().unwrap();
+            spool.write_all(&[0; 100]).unwrap();
+            inserted.push(disk.insert_spool(key, &fields, head(), spool).unwrap());
+        }
+        assert!(disk.get("a", &fields).is_none());
+        assert!(disk.get("c", &fields).is_some());
+        match &inserted[0].content {
+            Content::File(x, _) => assert!(!x.exists()),
+            _ => unreachable!(),
+        }
+
+        let mut spool = disk.spool().unwrap();
+        spool.write_all(&[0; 201]).unwrap();
+        assert!(disk.insert_spool("d", &fields, head(), spool).is_none());
+
+        assert_eq!(disk.purge(&Purge::Url("c".to_string())), 1);
+        assert!(disk.get("c", &fields).is_none());
+        drop(disk);
+        let disk = Disk::open(&dir, 300, 200).unwrap();
+        assert!(disk.get("c", &fields).is_none());
+        // files of the purged entry are gone, only the index is left
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn index_compaction() {
+        let dir = dir("compaction");
+        let fields = Fields::new();
+        let disk = Disk::open(&dir, 4096, 1024).unwrap();
+        for i in 0..COMPACT_MIN * 2 {
+            let mut spool = disk.spool().unwrap();
+            spool.write_all(i.to_string().as_bytes()).unwrap();
+            disk.insert_spool("a", &fields, head(), spool).unwrap();
+        }
+        // each replacement appends a removal and an entry, until written anew
+        let index = fs::read_to_string(dir.join(INDEX)).unwrap();
+        assert!(index.lines().count() <= COMPACT_MIN + 2);
+        drop(disk);
+
+        let disk = Disk::open(&dir, 4096, 1024).unwrap();
+        let entry = disk.get("a", &fields).unwrap();
+        assert_eq!(body(&entry), (COMPACT_MIN * 2 - 1).to_string().as_bytes());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,823 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+mod disk;
+
+use super::date;
+use super::header::Fields;
+use super::startline::{Method, StartLine};
+use super::upstream::Head;
+pub use disk::{Disk, Spool};
+
+// status codes cacheable by default
+const CACHEABLE: [u16; 8] = [200, 203, 204, 300, 301, 308, 404, 410];
+
+/// Directives of `Cache-Control`, names are lowercased
+fn directives(fields: &Fields) -> Vec<(String, Option<String>)> {
+    fields
+        .get_all("cache-control")
+        .flat_map(|x| x.split(|&x| x == b','))
+        .filter_map(|x| {
+            let x = String::from_utf8_lossy(x.trim_ascii());
+            if x.is_empty() {
+                return None;
+            }
+            Some(match x.split_once('=') {
+                Some((name, value)) => (
+                    name.trim().to_ascii_lowercase(),
+                    Some(value.trim().trim_matches('"').to_string()),
+                ),
+                None => (x.to_ascii_lowercase(), None),
+            })
+        })
+        .collect()
+}
+
+fn has(directives: &[(String, Option<String>)], name: &str) -> bool {
+    directives.iter().any(|(x, _)| x == name)
+}
+
+fn seconds(directives: &[(String, Option<String>)], name: &str) -> Option<Duration> {
+    directives
+        .iter()
+        .find(|(x, _)| x == name)
+        .and_then(|(_, x)| x.as_ref()?.parse().ok())
+        .map(Duration::from_secs)
+}
+
+/// Freshness lifetime of a response, None if it must not be stored
+///
+/// Responses without explicit lifetime are stored only with a validator,
+/// to be revalidated on every use.
+fn lifetime(head: &Head) -> Option<Duration> {
+    if !CACHEABLE.contains(&head.status) {
+        return None;
+    }
+    let fields = &head.fields;
+    let directives = directives(fields);
+    let vary_any = fields
+        .get_all("vary")
+        .flat_map(|x| x.split(|&x| x == b','))
+        .any(|x| x.trim_ascii() == b"*");
+    if has(&directives, "no-store")
+        || has(&directives, "private")
+        || fields.get("set-cookie").is_some()
+        || vary_any
+    {
+        return None;
+    }
+
+    let lifetime = if has(&directives, "no-cache") {
+        Duration::ZERO
+    } else {
+        seconds(&directives, "s-maxage")
+            .or_else(|| seconds(&directives, "max-age"))
+            .or_else(|| {
+                // an invalid Expires means already expired
+                let expires = date::parse(fields.get("expires")?).unwrap_or(UNIX_EPOCH);
+                let date = fields
+                    .get("date")
+                    .and_then(date::parse)
+                    .unwrap_or_else(SystemTime::now);
+                Some(expires.duration_since(date).unwrap_or_default())
+            })
+            .unwrap_or_default()
+    };
+    let validated = fields.get("etag").is_some() || fields.get("last-modified").is_some();
+    if lifetime.is_zero() && !validated {
+        None
+    } else {
+        Some(lifetime)
+    }
+}
+
+/// Where the body of a stored response is kept
+#[derive(Debug, Clone)]
+pub enum Content {
+    Memory(Vec<u8>),
+    // body file and its length
+    File(PathBuf, u64),
+}
+
+impl Content {
+    pub fn length(&self) -> u64 {
+        match self {
+            Content::Memory(x) => x.len() as u64,
+            Content::File(_, x) => *x,
+        }
+    }
+}
+
+/// Response stored in cache, body without transfer coding
+#[derive(Debug)]
+pub struct Entry {
+    head: Head,
+    pub content: Content,
+    stored: SystemTime,
+    // Age of the response when stored
+    age: Duration,
+    lifetime: Duration,
+}
+
+impl Entry {
+    fn new(mut head: Head, content: Content) -> Option<Self> {
+        let lifetime = lifetime(&head)?;
+        let age = head
+            .fields
+            .get("age")
+            .and_then(|x| std::str::from_utf8(x).ok()?.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_default();
+        head.fields.remove("transfer-encoding");
+        head.fields.remove("age");
+        head.fields
+            .set("Content-Length", content.length().to_string().as_bytes());
+        Some(Entry {
+            head,
+            content,
+            stored: SystemTime::now(),
+            age,
+            lifetime,
+        })
+    }
+
+    pub fn age(&self) -> Duration {
+        let elapsed = SystemTime::now()
+            .duration_since(self.stored)
+            .unwrap_or_default();
+        self.age + elapsed
+    }
+
+    pub fn fresh(&self) -> bool {
+        self.age() < self.lifetime
+    }
+
+    /// Whether the entry may be served within `directive`(`stale-while-revalidate`
+    /// or `stale-if-error`) of the response, `default` when the response has none
+    fn stale_within(&self, directive: &str, default: Duration) -> bool {
+        let directives = directives(&self.head.fields);
+        if has(&directives, "must-revalidate")
+            || has(&directives, "proxy-revalidate")
+            || has(&directives, "no-cache")
+        {
+            return false;
+        }
+        let window = seconds(&directives, directive).unwrap_or(default);
+        self.age().saturating_sub(self.lifetime) <= window
+    }
+
+    /// Head to send to client, with the current `Age`
+    pub fn head(&self) -> Head {
+        let mut head = self.head.clone();
+        head.fields
+            .set("Age", self.age().as_secs().to_string().as_bytes());
+        // meant for the cache only
+        head.fields.remove("surrogate-key");
+        head
+    }
+
+    /// Keys in `Surrogate-Key`, separated by space
+    fn surrogate_keys(&self) -> impl Iterator<Item = &[u8]> {
+        self.head
+            .fields
+            .get_all("surrogate-key")
+            .flat_map(|x| x.split(|x| x.is_ascii_whitespace()))
+            .filter(|x| !x.is_empty())
+    }
+
+    /// Header fields of the conditional request revalidating the entry
+    ///
+    /// Conditions of the client are cleared(empty value) when the entry has no such validator.
+    pub fn validators(&self) -> Vec<(String, String)> {
+        let fields = &self.head.fields;
+        let value = |name| {
+            fields
+                .get(name)
+                .map(|x| String::from_utf8_lossy(x).into_owned())
+                .unwrap_or_default()
+        };
+        vec![
+            ("If-None-Match".to_string(), value("etag")),
+            ("If-Modified-Since".to_string(), value("last-modified")),
+        ]
+    }
+
+    fn size(&self) -> u64 {
+        self.head.to_bytes().len() as u64 + self.content.length()
+    }
+}
+
+/// Result of a cache lookup
+pub enum Lookup {
+    Fresh(Arc<Entry>),
+    // stale within stale-while-revalidate, served while refreshed in background
+    Revalidate(Arc<Entry>),
+    Stale(Arc<Entry>),
+    Miss,
+}
+
+/// Entries to invalidate, in every variant
+#[derive(Debug, Clone, PartialEq)]
+pub enum Purge {
+    // host and target, `a.example.com/index.html?a=b`
+    Url(String),
+    // host and prefix of target
+    Prefix(String),
+    // key in `Surrogate-Key` of responses
+    Surrogate(String),
+}
+
+impl Purge {
+    /// Targets of a `PURGE` request
+    ///
+    /// Keys in `Surrogate-Key` of the request if any, otherwise the URL, a
+    /// target ending with `*` is a prefix.
+    pub fn request(host: &str, startline: &StartLine, fields: &Fields) -> Vec<Purge> {
+        let keys: Vec<_> = fields
+            .get_all("surrogate-key")
+            .flat_map(|x| x.split(|x| x.is_ascii_whitespace()))
+            .filter(|x| !x.is_empty())
+            .map(|x| Purge::Surrogate(String::from_utf8_lossy(x).into_owned()))
+            .collect();
+        if !keys.is_empty() {
+            return keys;
+        }
+        let (_, origin) = startline.split_target();
+        let url = format!("{}{}", host, String::from_utf8_lossy(origin));
+        match url.strip_suffix('*') {
+            Some(x) => vec![Purge::Prefix(x.to_string())],
+            None => vec![Purge::Url(url)],
+        }
+    }
+
+    fn matches(&self, key: &str, entry: &Entry) -> bool {
+        let primary = key.split('\n').next().unwrap_or_default();
+        let url = primary.strip_prefix("GET ").unwrap_or(primary);
+        match self {
+            Purge::Url(x) => url == x,
+            Purge::Prefix(x) => url.starts_with(x.as_str()),
+            Purge::Surrogate(x) => entry.surrogate_keys().any(|key| key == x.as_bytes()),
+        }
+    }
+}
+
+/// Header names in `Vary` of a response, lowercased
+fn vary(head: &Head) -> Vec<String> {
+    head.fields
+        .get_all("vary")
+        .flat_map(|x| x.split(|&x| x == b','))
+        .map(|x| String::from_utf8_lossy(x.trim_ascii()).to_ascii_lowercase())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+/// Full key of the variant selected by request header fields
+fn variant(primary: &str, vary: &[String], fields: &Fields) -> String {
+    let mut key = primary.to_string();
+    for name in vary {
+        let values: Vec<_> = fields
+            .get_all(name)
+            .map(|x| String::from_utf8_lossy(x).into_owned())
+            .collect();
+        key.push_str(&format!("\n{}:{}", name, values.join(",")));
+    }
+    key
+}
+
+/// Entries of a tier in least recently used order
+#[derive(Default)]
+struct Store {
+    // header names in `Vary` and number of entries of each primary key
+    variants: HashMap<String, (Vec<String>, usize)>,
+    entries: HashMap<String, (Arc<Entry>, u64)>,
+    // least recently used first
+    order: BTreeMap<u64, String>,
+    size: u64,
+    tick: u64,
+}
+
+impl Store {
+    fn remove(&mut self, key: &str) -> Option<Arc<Entry>> {
+        let (entry, tick) = self.entries.remove(key)?;
+        self.order.remove(&tick);
+        self.size -= entry.size();
+        let primary = key.split('\n').next().unwrap_or_default();
+        if let Some((_, count)) = self.variants.get_mut(primary) {
+            *count -= 1;
+            if *count == 0 {
+                self.variants.remove(primary);
+            }
+        }
+        Some(entry)
+    }
+
+    /// Entry of the variant selected by request, marked as recently used
+    fn get(&mut self, primary: &str, fields: &Fields) -> Option<Arc<Entry>> {
+        let (vary, _) = self.variants.get(primary)?;
+        let key = variant(primary, vary, fields);
+        self.tick += 1;
+        let tick = self.tick;
+        let (entry, old) = self.entries.get_mut(&key)?;
+        self.order.remove(old);
+        *old = tick;
+        self.order.insert(tick, key);
+        Some(entry.clone())
+    }
+
+    /// Remove entries matching the purge, returning them
+    fn purge(&mut self, purge: &Purge) -> Vec<Arc<Entry>> {
+        let keys: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(key, (entry, _))| purge.matches(key, entry))
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.iter().filter_map(|x| self.remove(x)).collect()
+    }
+
+    /// Insert as the most recently used entry, returning the replaced and evicted ones
+    fn insert(
+        &mut self,
+        key: String,
+        vary: Vec<String>,
+        entry: Arc<Entry>,
+        capacity: u64,
+    ) -> Vec<Arc<Entry>> {
+        let mut removed: Vec<_> = self.remove(&key).into_iter().collect();
+        let primary = key.split('\n').next().unwrap_or_default().to_string();
+        let variants = self.variants.entry(primary).or_insert((vary.clone(), 0));
+        variants.0 = vary;
+        variants.1 += 1;
+        self.tick += 1;
+        self.size += entry.size();
+        self.entries.insert(key.clone(), (entry, self.tick));
+        self.order.insert(self.tick, key);
+
+        while self.size > capacity {
+            let key = match self.order.first_key_value() {
+                Some((_, x)) => x.clone(),
+                None => break,
+            };
+            removed.extend(self.remove(&key));
+        }
+        removed
+    }
+}
+
+/// Shared cache of upstream responses, bounded by bytes and evicting least recently used
+///
+/// Responses larger than `max_object` go to the disk tier when there is one.
+/// Stale responses are served for `stale_while_revalidate` while refreshed,
+/// and for `stale_if_error` when upstream fails, unless the response says otherwise.
+/// Concurrent fetches of a key wait on the first one for up to `coalesce_timeout`.
+pub struct Cache {
+    capacity: u64,
+    max_object: u64,
+    store: Mutex<Store>,
+    disk: Option<Disk>,
+    stale_while_revalidate: Duration,
+    stale_if_error: Duration,
+    coalesce_timeout: Duration,
+    // fetches from upstream in flight by key
+    inflight: Mutex<HashMap<String, Arc<Flight>>>,
+}
+
+#[derive(Default)]
+struct Flight {
+    done: Mutex<bool>,
+    finished: Condvar,
+}
+
+/// Claim on fetching a key from upstream, waiters are woken when dropped
+pub struct Fetch<'a> {
+    cache: &'a Cache,
+    key: String,
+    flight: Arc<Flight>,
+}
+
+impl Drop for Fetch<'_> {
+    fn drop(&mut self) {
+        self.cache.inflight.lock().unwrap().remove(&self.key);
+        *self.flight.done.lock().unwrap() = true;
+        self.flight.finished.notify_all();
+    }
+}
+
+impl std::fmt::Debug for Cache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cache")
+            .field("capacity", &self.capacity)
+            .field("max_object", &self.max_object)
+            .field("disk", &self.disk)
+            .field("stale_while_revalidate", &self.stale_while_revalidate)
+            .field("stale_if_error", &self.stale_if_error)
+            .finish()
+    }
+}
+
+impl Cache {
+    pub fn new(capacity: u64, max_object: u64) -> Self {
+        Cache {
+            capacity,
+            max_object,
+            store: Mutex::new(Store::default()),
+            disk: None,
+            stale_while_revalidate: Duration::ZERO,
+            stale_if_error: Duration::ZERO,
+            coalesce_timeout: Duration::ZERO,
+            inflight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn disk(mut self, disk: Disk) -> Self {
+        self.disk = Some(disk);
+        self
+    }
+
+    /// How long stale responses are served by default, while refreshed and on error
+    pub fn stale(mut self, while_revalidate: Duration, if_error: Duration) -> Self {
+        self.stale_while_revalidate = while_revalidate;
+        self.stale_if_error = if_error;
+        self
+    }
+
+    /// How long a request waits on the fetch of the same key in flight
+    pub fn coalesce(mut self, timeout: Duration) -> Self {
+        self.coalesce_timeout = timeout;
+        self
+    }
+
+    /// Whether the response may be stored at all
+    pub fn storable(head: &Head) -> bool {
+        lifetime(head).is_some()
+    }
+
+    /// Largest body to be stored in memory
+    pub fn max_object(&self) -> u64 {
+        self.max_object
+    }
+
+    /// Largest body to be stored on disk, None without disk tier
+    pub fn disk_max_object(&self) -> Option<u64> {
+        self.disk.as_ref().map(|x| x.max_object())
+    }
+
+    /// Key of the request, None when it should bypass the cache
+    pub fn key(host: &str, startline: &StartLine, fields: &Fields) -> Option<String> {
+        if startline.method != Method::GET
+            || fields.get("authorization").is_some()
+            || fields.get("range").is_some()
+            || fields.get("upgrade").is_some()
+            || has(&directives(fields), "no-store")
+        {
+            return None;
+        }
+        let (_, origin) = startline.split_target();
+        Some(format!("GET {}{}", host, String::from_utf8_lossy(origin)))
+    }
+
+    pub fn lookup(&self, key: &str, fields: &Fields) -> Lookup {
+        let entry = self.store.lock().unwrap().get(key, fields);
+        let entry = match entry.or_else(|| self.disk.as_ref()?.get(key, fields)) {
+            Some(x) => x,
+            None => return Lookup::Miss,
+        };
+
+        let directives = directives(fields);
+        let revalidate = has(&directives, "no-cache")
+            || seconds(&directives, "max-age").is_some_and(|x| entry.age() >= x);
+        if entry.fresh() && !revalidate {
+            Lookup::Fresh(entry)
+        } else if !revalidate
+            && entry.stale_within("stale-while-revalidate", self.stale_while_revalidate)
+        {
+            Lookup::Revalidate(entry)
+        } else {
+            Lookup::Stale(entry)
+        }
+    }
+
+    /// Whether a stale entry may answer when upstream fails
+    pub fn usable_on_error(&self, entry: &Entry) -> bool {
+        entry.stale_within("stale-if-error", self.stale_if_error)
+    }
+
+    /// Claim the fetch of a key, None when it is already in flight
+    pub fn fetch_claim(&self, key: &str) -> Option<Fetch<'_>> {
+        let mut inflight = self.inflight.lock().unwrap();
+        if inflight.contains_key(key) {
+            return None;
+        }
+        let flight = Arc::new(Flight::default());
+        inflight.insert(key.to_string(), flight.clone());
+        Some(Fetch {
+            cache: self,
+            key: key.to_string(),
+            flight,
+        })
+    }
+
+    /// Block until the fetch of a key in flight is done, false when timed out
+    pub fn wait(&self, key: &str) -> bool {
+        let flight = match self.inflight.lock().unwrap().get(key) {
+            Some(x) => x.clone(),
+            None => return true,
+        };
+        let done = flight.done.lock().unwrap();
+        let (done, _) = flight
+            .finished
+            .wait_timeout_while(done, self.coalesce_timeout, |x| !*x)
+            .unwrap();
+        *done
+    }
+
+    /// Store the response of a request in memory, None if it is not storable
+    pub fn insert(
+        &self,
+        key: &str,
+        fields: &Fields,
+        head: Head,
+        body: Vec<u8>,
+    ) -> Option<Arc<Entry>> {
+        if body.len() as u64 > self.max_object {
+            return None;
+        }
+        let vary = vary(&head);
+        let entry = Arc::new(Entry::new(head, Content::Memory(body))?);
+        if entry.size() > self.capacity {
+            return None;
+        }
+        let full = variant(key, &vary, fields);
+        let mut store = self.store.lock().unwrap();
+        store.insert(full, vary, entry.clone(), self.capacity);
+        Some(entry)
+    }
+
+    /// Invalidate entries in memory and on disk, returning how many were removed
+    pub fn purge(&self, purge: &Purge) -> usize {
+        let memory = self.store.lock().unwrap().purge(purge).len();
+        memory
+            + self
+                .disk
+                .as_ref()
+                .map(|x| x.purge(purge))
+                .unwrap_or_default()
+    }
+
+    /// File to write a body too large for memory into, None without disk tier
+    pub fn spool(&self) -> Option<Spool> {
+        self.disk.as_ref()?.spool().ok()
+    }
+
+    /// Store the response with body written to spool on disk, None if it is not storable
+    pub fn insert_spool(
+        &self,
+        key: &str,
+        fields: &Fields,
+        head: Head,
+        spool: Spool,
+    ) -> Option<Arc<Entry>> {
+        self.disk.as_ref()?.insert_spool(key, fields, head, spool)
+    }
+
+    /// Update a stale entry with the `304 Not Modified` answering its revalidation
+    pub fn refresh(
+        &self,
+        key: &str,
+        fields: &Fields,
+        entry: &Entry,
+        head: &Head,
+    ) -> Option<Arc<Entry>> {
+        let mut merged = entry.head.clone();
+        let mut names: Vec<&[u8]> = vec![];
+        for (name, _) in head.fields.iter() {
+            if !names.iter().any(|x| x.eq_ignore_ascii_case(name)) {
+                names.push(name);
+            }
+        }
+        for name in names {
+            let name = String::from_utf8_lossy(name);
+            if name.eq_ignore_ascii_case("content-length")
+                || name.eq_ignore_ascii_case("transfer-encoding")
+            {
+                continue;
+            }
+            merged.fields.remove(&name);
+            for value in head.fields.get_all(&name) {
+                merged
+                    .fields
+                    .push(&[name.as_bytes(), b": ", value].concat());
+            }
+        }
+        match &entry.content {
+            Content::Memory(x) => self.insert(key, fields, merged, x.clone()),
+            Content::File(..) => {
+                self.disk
+                    .as_ref()?
+                    .insert(key, fields, merged, entry.content.clone())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn head(lines: &str) -> Head {
+        Head::parse(format!("HTTP/1.1 200 OK\r\n{}\r\n", lines).as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn freshness() {
+        let max_age = |lines| lifetime(&head(lines)).map(|x| x.as_secs());
+        assert_eq!(
+            max_age("Cache-Control: max-age=60, s-maxage=120\r\n"),
+            Some(120)
+        );
+        assert_eq!(
+            max_age("Cache-Control: public, max-age=\"60\"\r\n"),
+            Some(60)
+        );
+        assert_eq!(
+            max_age(
+                "Date: Sun, 06 Nov 1994 08:49:37 GMT\r\nExpires: Sun, 06 Nov 1994 08:50:37 GMT\r\n"
+            ),
+            Some(60)
+        );
+        assert_eq!(
+            max_age("Cache-Control: no-cache\r\nETag: \"a\"\r\n"),
+            Some(0)
+        );
+        assert_eq!(max_age("Cache-Control: private, max-age=60\r\n"), None);
+        assert_eq!(
+            max_age("Cache-Control: max-age=60\r\nSet-Cookie: a=1\r\n"),
+            None
+        );
+        assert_eq!(max_age("Content-Type: text/plain\r\n"), None);
+    }
+
+    #[test]
+    fn lookup() {
+        let cache = Cache::new(4096, 1024);
+        let startline: StartLine = b"GET /a?b=c HTTP/1.1".as_ref().try_into().unwrap();
+        let mut en = Fields::new();
+        en.push(b"Accept-Language: en");
+        let mut fr = Fields::new();
+        fr.push(b"Accept-Language: fr");
+
+        let key = Cache::key("a.example.com", &startline, &en).unwrap();
+        assert_eq!(key, "GET a.example.com/a?b=c");
+        assert!(matches!(cache.lookup(&key, &en), Lookup::Miss));
+
+        let response = head("Cache-Control: max-age=60\r\nVary: Accept-Language\r\n");
+        cache
+            .insert(&key, &en, response, b"hello".to_vec())
+            .unwrap();
+        match cache.lookup(&key, &en) {
+            Lookup::Fresh(x) => {
+                assert!(matches!(&x.content, Content::Memory(x) if x == b"hello"));
+                assert_eq!(x.head().fields.get("content-length"), Some(&b"5"[..]));
+            }
+            _ => panic!("stored response should be fresh"),
+        }
+        assert!(matches!(cache.lookup(&key, &fr), Lookup::Miss));
+
+        let mut no_cache = en.clone();
+        no_cache.push(b"Cache-Control: no-cache");
+        assert!(matches!(cache.lookup(&key, &no_cache), Lookup::Stale(_)));
+
+        let mut authorized = en.clone();
+        authorized.push(b"Authorization: Basic YQ==");
+        assert_eq!(Cache::key("a.example.com", &startline, &authorized), None);
+    }
+
+    #[test]
+    fn eviction() {
+        let cache = Cache::new(400, 200);
+        let fields = Fields::new();
+        let response = head("Cache-Control: max-age=60\r\n");
+        cache
+            .insert("a", &fields, response.clone(), vec![0; 100])
+            .unwrap();
+        cache
+            .insert("b", &fields, response.clone(), vec![0; 100])
+            .unwrap();
+        assert!(matches!(cache.lookup("a", &fields), Lookup::Fresh(_)));
+        // b is the least recently used now
+        cache
+            .insert("c", &fields, response.clone(), vec![0; 100])
+            .unwrap();
+        assert!(matches!(cache.lookup("a", &fields), Lookup::Fresh(_)));
+        assert!(matches!(cache.lookup("b", &fields), Lookup::Miss));
+        assert!(cache.insert("d", &fields, response, vec![0; 201]).is_none());
+    }
+
+    #[test]
+    fn revalidation() {
+        let cache = Cache::new(4096, 1024);
+        let fields = Fields::new();
+        let response = head("Cache-Control: no-cache\r\nETag: \"v1\"\r\nX-Version: 1\r\n");
+        let entry = cache
+            .insert("a", &fields, response, b"hello".to_vec())
+            .unwrap();
+        assert!(!entry.fresh());
+        assert_eq!(entry.validators()[0].1, "\"v1\"");
+        assert_eq!(entry.validators()[1].1, "");
+
+        let not_modified = Head::parse(
+            b"HTTP/1.1 304 Not Modified\r\nCache-Control: max-age=60\r\nX-Version: 2\r\n\r\n",
+        )
+        .unwrap();
+        let entry = cache.refresh("a", &fields, &entry, &not_modified).unwrap();
+        assert!(entry.fresh());
+        assert_eq!(entry.head().fields.get("x-version"), Some(&b"2"[..]));
+        assert_eq!(entry.content.length(), 5);
+    }
+
+    #[test]
+    fn stale() {
+        let cache = Cache::new(4096, 1024).stale(Duration::ZERO, Duration::from_secs(60));
+        let fields = Fields::new();
+        let swr = "Cache-Control: max-age=0, stale-while-revalidate=60\r\nETag: \"a\"\r\n";
+        cache
+            .insert("a", &fields, head(swr), b"a".to_vec())
+            .unwrap();
+        match cache.lookup("a", &fields) {
+            Lookup::Revalidate(x) => assert!(cache.usable_on_error(&x)),
+            _ => panic!("entry within stale-while-revalidate should be served"),
+        }
+        let mut no_cache = Fields::new();
+        no_cache.push(b"Cache-Control: no-cache");
+        assert!(matches!(cache.lookup("a", &no_cache), Lookup::Stale(_)));
+
+        let strict = "Cache-Control: max-age=0, must-revalidate\r\nETag: \"b\"\r\n";
+        cache
+            .insert("b", &fields, head(strict), b"b".to_vec())
+            .unwrap();
+        match cache.lookup("b", &fields) {
+            Lookup::Stale(x) => assert!(!cache.usable_on_error(&x)),
+            _ => panic!("must-revalidate should not be served stale"),
+        }
+    }
+
+    #[test]
+    fn purge() {
+        let cache = Cache::new(4096, 1024);
+        let fields = Fields::new();
+        let tagged = "Cache-Control: max-age=60\r\nSurrogate-Key: post-1  home\r\n";
+        for key in [
+            "GET a.com/",
+            "GET a.com/assets/a.css",
+            "GET a.com/assets/b.css",
+        ] {
+            cache
+                .insert(key, &fields, head(tagged), b"a".to_vec())
+                .unwrap();
+        }
+        let startline: StartLine = b"PURGE /assets/* HTTP/1.1".as_ref().try_into().unwrap();
+        let purges = Purge::request("a.com", &startline, &fields);
+        assert_eq!(purges, vec![Purge::Prefix("a.com/assets/".to_string())]);
+        assert_eq!(cache.purge(&purges[0]), 2);
+
+        match cache.lookup("GET a.com/", &fields) {
+            Lookup::Fresh(x) => assert_eq!(x.head().fields.get("surrogate-key"), None),
+            _ => panic!("entry outside of prefix should be kept"),
+        }
+        let mut keyed = Fields::new();
+        keyed.push(b"Surrogate-Key: home");
+        let purges = Purge::request("a.com", &startline, &keyed);
+        assert_eq!(cache.purge(&purges[0]), 1);
+        assert!(matches!(cache.lookup("GET a.com/", &fields), Lookup::Miss));
+    }
+
+    #[test]
+    fn coalescing() {
+        let cache = Arc::new(Cache::new(4096, 1024).coalesce(Duration::from_secs(5)));
+        let fields = Fields::new();
+        let fetch = cache.fetch_claim("a").unwrap();
+        assert!(cache.fetch_claim("a").is_none());
+
+        let waiter = {
+            let cache = cache.clone();
+            std::thread::spawn(move || {
+                let done = cache.wait("a");
+                (
+                    done,
+                    matches!(cache.lookup("a", &Fields::new()), Lookup::Fresh(_)),
+                )
+            })
+        };
+        let response = head("Cache-Control: max-age=60\r\n");
+        cache.insert("a", &fields, response, b"a".to_vec()).unwrap();
+        drop(fetch);
+        assert_eq!(waiter.join().unwrap(), (true, true));
+        assert!(cache.fetch_claim("a").is_some());
+
+        let cache = Cache::new(4096, 1024).coalesce(Duration::from_millis(10));
+        let _fetch = cache.fetch_claim("a").unwrap();
+        assert!(!cache.wait("a"));
+    }
+}
@@ -0,0 +1,331 @@
+use flate2::write::GzEncoder;
+use std::io;
+
+use super::header::Fields;
+use super::startline::{HttpVersion, Method, StartLine};
+use super::upstream::Head;
+
+const CHUNK_SIZE: usize = 16384;
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Coding {
+    Brotli,
+    Gzip,
+}
+
+impl Coding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Coding::Brotli => "br",
+            Coding::Gzip => "gzip",
+        }
+    }
+}
+
+/// Compress responses on the fly
+///
+/// `types` are prefixes of eligible content types(`text/` matches `text/html`),
+/// responses smaller than `min_size` are sent as is.
+#[derive(Debug)]
+pub struct Compress {
+    pub types: Vec<String>,
+    pub min_size: u64,
+}
+
+impl Default for Compress {
+    fn default() -> Self {
+        Compress {
+            types: [
+                "text/",
+                "application/json",
+                "application/javascript",
+                "application/xml",
+                "application/wasm",
+                "image/svg+xml",
+            ]
+            .into_iter()
+            .map(|x| x.to_string())
+            .collect(),
+            min_size: 1024,
+        }
+    }
+}
+
+impl Compress {
+    /// Pick the coding accepted by the client, brotli first
+    ///
+    /// Clients before HTTP/1.1 get the body as is, they can not read it chunked.
+    pub fn negotiate(&self, startline: &StartLine, fields: &Fields) -> Compression<'_> {
+        let coding = if startline.method == Method::HEAD || startline.version != HttpVersion::HTTP1
+        {
+            None
+        } else if fields.accepts("br") {
+            Some(Coding::Brotli)
+        } else if fields.accepts("gzip") {
+            Some(Coding::Gzip)
+        } else {
+            None
+        };
+        Compression {
+            config: self,
+            coding,
+        }
+    }
+
+    fn eligible(&self, head: &Head) -> bool {
+        let fields = &head.fields;
+        if !(200..300).contains(&head.status) || head.status == 204 || head.status == 206 {
+            return false;
+        }
+        let encoded = fields
+            .get("content-encoding")
+            .map(|x| !x.eq_ignore_ascii_case(b"identity"))
+            .unwrap_or(false);
+        let no_transform = fields
+            .get_all("cache-control")
+            .flat_map(|x| x.split(|&x| x == b','))
+            .any(|x| x.trim_ascii().eq_ignore_ascii_case(b"no-transform"));
+        if encoded || no_transform {
+            return false;
+        }
+
+        let content_type = fields.get("content-type").unwrap_or_default();
+        let content_type = content_type
+            .split(|&x| x == b';')
+            .next()
+            .unwrap_or_default()
+            .trim_ascii()
+            .to_ascii_lowercase();
+        let small = fields
+            .get("content-length")
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| x.parse::<u64>().ok())
+            .map(|x| x < self.min_size)
+            .unwrap_or(false);
+        !small
+            && self
+                .types
+                .iter()
+                .any(|x| content_type.starts_with(x.as_bytes()))
+    }
+}
+
+/// Compression settings with the coding negotiated for a request
+#[derive(Debug)]
+pub struct Compression<'a> {
+    config: &'a Compress,
+    coding: Option<Coding>,
+}
+
+impl<'a> Compression<'a> {
+    /// Adjust the response head, returning the coding when the body should be compressed
+    ///
+    /// Eligible responses always get `Vary: Accept-Encoding`, as the same URL
+    /// is answered differently depending on the client.
+    pub fn apply(&self, head: &mut Head) -> Option<Coding> {
+        if !self.config.eligible(head) {
+            return None;
+        }
+        let fields = &mut head.fields;
+        let vary = fields.get_all("vary").fold(String::new(), |mut output, x| {
+            if !output.is_empty() {
+                output.push_str(", ");
+            }
+            output.push_str(&String::from_utf8_lossy(x));
+            output
+        });
+        let varied = vary.split(',').any(|x| {
+            let x = x.trim();
+            x == "*" || x.eq_ignore_ascii_case("accept-encoding")
+        });
+        if vary.is_empty() {
+            fields.set("Vary", b"Accept-Encoding");
+        } else if !varied {
+            fields.set("Vary", format!("{}, Accept-Encoding", vary).as_bytes());
+        }
+
+        let coding = self.coding?;
+        fields.remove("content-length");
+        fields.set("Transfer-Encoding", b"chunked");
+        fields.set("Content-Encoding", coding.name().as_bytes());
+        // the compressed body is no longer byte-for-byte the same representation
+        if let Some(etag) = fields.get("etag") {
+            if !etag.starts_with(b"W/") {
+                let etag = [b"W/", etag].concat();
+                fields.set("ETag", &etag);
+            }
+        }
+        Some(coding)
+    }
+}
+
+/// Write each piece of data as a chunk of chunked transfer coding
+pub struct Chunked<W>
+where
+    W: io::Write,
+{
+    writer: W,
+}
+
+impl<W> Chunked<W>
+where
+    W: io::Write,
+{
+    pub fn new(writer: W) -> Self {
+        Chunked { writer }
+    }
+    /// Write the last chunk
+    pub fn finish(mut self) -> Result<W, io::Error> {
+        self.writer.write_all(b"0\r\n\r\n")?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W> io::Write for Chunked<W>
+where
+    W: io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut output = format!("{:x}\r\n", buf.len()).into_bytes();
+        output.extend_from_slice(buf);
+        output.extend_from_slice(b"\r\n");
+        self.writer.write_all(&output)?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+enum Encoder<W>
+where
+    W: io::Write,
+{
+    Brotli(Box<brotli::CompressorWriter<W>>),
+    Gzip(GzEncoder<W>),
+}
+
+impl<W> Encoder<W>
+where
+    W: io::Write,
+{
+    fn new(coding: Coding, writer: W) -> Self {
+        match coding {
+            Coding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                writer,
+                CHUNK_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+            Coding::Gzip => Encoder::Gzip(GzEncoder::new(writer, flate2::Compression::default())),
+        }
+    }
+    fn finish(self) -> Result<W, io::Error> {
+        match self {
+            Encoder::Brotli(x) => Ok(x.into_inner()),
+            Encoder::Gzip(x) => x.finish(),
+        }
+    }
+}
+
+impl<W> io::Write for Encoder<W>
+where
+    W: io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Brotli(x) => x.write(buf),
+            Encoder::Gzip(x) => x.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Brotli(x) => x.flush(),
+            Encoder::Gzip(x) => x.flush(),
+        }
+    }
+}
+
+/// Compress the body and send it with chunked transfer coding
+pub fn compress<R, W>(body: &mut R, coding: Coding, writer: W) -> Result<(), io::Error>
+where
+    R: io::Read,
+    W: io::Write,
+{
+    // buffer in front of Chunked, so chunks are not as small as each write of the encoder
+    let buffer = io::BufWriter::with_capacity(CHUNK_SIZE, Chunked::new(writer));
+    let mut encoder = Encoder::new(coding, buffer);
+    io::copy(body, &mut encoder)?;
+    let buffer = encoder.finish()?;
+    let chunked = buffer.into_inner().map_err(|x| x.into_error())?;
+    chunked.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn head(lines: &str) -> Head {
+        Head::parse(format!("HTTP/1.1 200 OK\r\n{}\r\n", lines).as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn negotiation() {
+        let config = Compress::default();
+        let startline: StartLine = b"GET / HTTP/1.1".as_ref().try_into().unwrap();
+        let mut fields = Fields::new();
+        fields.push(b"Accept-Encoding: gzip, br;q=0");
+        let compression = config.negotiate(&startline, &fields);
+
+        let mut json = head("Content-Type: application/json\r\nContent-Length: 4096\r\nETag: \"a\"\r\nVary: Origin\r\n");
+        assert_eq!(compression.apply(&mut json), Some(Coding::Gzip));
+        assert_eq!(json.fields.get("content-length"), None);
+        assert_eq!(json.fields.get("transfer-encoding"), Some(&b"chunked"[..]));
+        assert_eq!(json.fields.get("etag"), Some(&b"W/\"a\""[..]));
+        assert_eq!(
+            json.fields.get("vary"),
+            Some(&b"Origin, Accept-Encoding"[..])
+        );
+
+        let mut small = head("Content-Type: text/html\r\nContent-Length: 10\r\n");
+        assert_eq!(compression.apply(&mut small), None);
+        let mut image = head("Content-Type: image/png\r\n");
+        assert_eq!(compression.apply(&mut image), None);
+        let mut encoded = head("Content-Type: text/html\r\nContent-Encoding: gzip\r\n");
+        assert_eq!(compression.apply(&mut encoded), None);
+
+        let compression = config.negotiate(&startline, &Fields::new());
+        let mut html = head("Content-Type: text/html\r\n");
+        assert_eq!(compression.apply(&mut html), None);
+        assert_eq!(html.fields.get("vary"), Some(&b"Accept-Encoding"[..]));
+
+        let startline: StartLine = b"GET / HTTP/1.0".as_ref().try_into().unwrap();
+        let compression = config.negotiate(&startline, &fields);
+        let mut html = head("Content-Type: text/html\r\n");
+        assert_eq!(compression.apply(&mut html), None);
+        assert_eq!(html.fields.get("transfer-encoding"), None);
+    }
+
+    #[test]
+    fn gzip_chunked() {
+        let mut output = vec![];
+        compress(&mut b"hello world".as_ref(), Coding::Gzip, &mut output).unwrap();
+        assert!(output.ends_with(b"\r\n0\r\n\r\n"));
+
+        let split = output.windows(2).position(|x| x == b"\r\n").unwrap();
+        let size = usize::from_str_radix(std::str::from_utf8(&output[..split]).unwrap(), 16);
+        let data = &output[split + 2..split + 2 + size.unwrap()];
+        let mut plain = String::new();
+        GzDecoder::new(data).read_to_string(&mut plain).unwrap();
+        assert_eq!(plain, "hello world");
+    }
+}
@@ -0,0 +1,427 @@
+use futures::AsyncWriteExt;
+use std::fs;
+use std::io::{self, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use std::{marker, str};
+
+use super::date;
+use super::header::Fields;
+use super::response::Response;
+use super::startline::{Method, StartLine};
+use crate::poll::network::WriteWrapper;
+
+// precompressed siblings, in order of preference
+const ENCODINGS: [(&str, &str); 2] = [("br", ".br"), ("gzip", ".gz")];
+
+fn mime(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|x| x.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Percent-decode, None when malformed
+pub fn decode(input: &[u8]) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut iter = input.iter();
+    while let Some(&x) = iter.next() {
+        if x == b'%' {
+            let high = (*iter.next()? as char).to_digit(16)?;
+            let low = (*iter.next()? as char).to_digit(16)?;
+            output.push((high * 16 + low) as u8);
+        } else {
+            output.push(x);
+        }
+    }
+    Some(output)
+}
+
+/// Relative file path of a request path, `None` when it would escape the root
+fn sanitize(path: &[u8]) -> Option<PathBuf> {
+    let path = String::from_utf8(decode(path)?).ok()?;
+    if path.contains(['\0', '\\']) {
+        return None;
+    }
+    let mut output = PathBuf::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            x => output.push(x),
+        }
+    }
+    Some(output)
+}
+
+/// Single byte range of `Range`, inclusive
+///
+/// `None` when the field should be ignored, `Some(Err(()))` when unsatisfiable.
+fn range(value: &[u8], length: u64) -> Option<Result<(u64, u64), ()>> {
+    let value = str::from_utf8(value).ok()?.trim();
+    let spec = value.strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        // multipart ranges are not supported, send the whole file
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let range = if start.is_empty() {
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 {
+            return Some(Err(()));
+        }
+        (length.saturating_sub(suffix), length.wrapping_sub(1))
+    } else {
+        let start: u64 = start.parse().ok()?;
+        if start >= length {
+            return Some(Err(()));
+        }
+        let end = match end {
+            "" => length.wrapping_sub(1),
+            x => x.parse::<u64>().ok()?.min(length.wrapping_sub(1)),
+        };
+        if end < start {
+            return None;
+        }
+        (start, end)
+    };
+    if length == 0 {
+        Some(Err(()))
+    } else {
+        Some(Ok(range))
+    }
+}
+
+fn etag_matches(value: &[u8], etag: &str) -> bool {
+    value.split(|&x| x == b',').any(|x| {
+        let x = x.trim_ascii();
+        x == b"*" || x.strip_prefix(b"W/").unwrap_or(x) == etag.as_bytes()
+    })
+}
+
+/// Part of a file to send as body
+#[derive(Debug)]
+pub struct Body {
+    file: fs::File,
+    start: u64,
+    length: u64,
+}
+
+/// Serve files under `root`
+///
+/// Directories are answered with `index`, missing files with `fallback` when set,
+/// so single page applications can route on the client.
+#[derive(Debug)]
+pub struct Files {
+    pub root: PathBuf,
+    pub index: String,
+    pub fallback: Option<String>,
+}
+
+impl Files {
+    /// Locate the file of a request path, or the response answering it instead
+    fn locate(&self, path: &[u8], query: &[u8]) -> Result<PathBuf, Response> {
+        let relative = sanitize(path).ok_or_else(|| Response::new(400))?;
+        let mut file = self.root.join(relative);
+        if file.is_dir() {
+            if !path.ends_with(b"/") {
+                let location = format!(
+                    "{}/{}",
+                    String::from_utf8_lossy(path),
+                    String::from_utf8_lossy(query)
+                );
+                return Err(Response::new(301).header("Location", &location));
+            }
+            file.push(&self.index);
+        }
+
+        // symbolic links must not lead outside of root either
+        let root = self.root.canonicalize().map_err(|_| Response::new(404))?;
+        match file.canonicalize() {
+            Ok(x) if x.starts_with(&root) && x.is_file() => Ok(x),
+            _ => match &self.fallback {
+                Some(fallback) => {
+                    let file = sanitize(fallback.as_bytes())
+                        .map(|x| self.root.join(x))
+                        .and_then(|x| x.canonicalize().ok());
+                    match file {
+                        Some(x) if x.starts_with(&root) && x.is_file() => Ok(x),
+                        _ => Err(Response::new(404)),
+                    }
+                }
+                None => Err(Response::new(404)),
+            },
+        }
+    }
+
+    /// Build the response of a request, with the part of file to send after it
+    pub fn respond(&self, startline: &StartLine, fields: &Fields) -> (Response, Option<Body>) {
+        if !matches!(startline.method, Method::GET | Method::HEAD) {
+            return (Response::new(405).header("Allow", "GET, HEAD"), None);
+        }
+        let (_, origin) = startline.split_target();
+        let split = origin
+            .iter()
+            .position(|&x| x == b'?')
+            .unwrap_or(origin.len());
+        let (path, query) = origin.split_at(split);
+
+        let path = match self.locate(path, query) {
+            Ok(x) => x,
+            Err(x) => return (x, None),
+        };
+        match self.open(&path, fields) {
+            Ok(x) => x,
+            Err(_) => (Response::new(404), None),
+        }
+    }
+
+    fn open(&self, path: &Path, fields: &Fields) -> Result<(Response, Option<Body>), io::Error> {
+        let mut response = Response::new(200).header("Content-Type", mime(path));
+
+        // prefer a precompressed sibling accepted by the client
+        let root = self.root.canonicalize()?;
+        let mut chosen = None;
+        let mut vary = false;
+        for (coding, suffix) in ENCODINGS {
+            let mut sibling = path.as_os_str().to_owned();
+            sibling.push(suffix);
+            // held to root as the file itself is
+            let sibling = match PathBuf::from(sibling).canonicalize() {
+                Ok(x) if x.starts_with(&root) && x.is_file() => x,
+                _ => continue,
+            };
+            vary = true;
+            if chosen.is_none() && fields.accepts(coding) {
+                chosen = Some((coding, sibling));
+            }
+        }
+        if vary {
+            response = response.header("Vary", "Accept-Encoding");
+        }
+        let (file, suffix) = match &chosen {
+            Some((coding, sibling)) => {
+                response = response.header("Content-Encoding", coding);
+                (fs::File::open(sibling)?, format!("-{}", coding))
+            }
+            None => (fs::File::open(path)?, String::new()),
+        };
+
+        let metadata = file.metadata()?;
+        let length = metadata.len();
+        let modified = metadata.modified()?;
+        let seconds = modified
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let etag = format!("\"{:x}-{:x}{}\"", seconds, length, suffix);
+        let last_modified = date::format(modified);
+        response = response
+            .header("Last-Modified", &last_modified)
+            .header("ETag", &etag)
+            .header("Accept-Ranges", "bytes");
+
+        // If-None-Match takes precedence over If-Modified-Since
+        let not_modified = match fields.get("if-none-match") {
+            Some(x) => etag_matches(x, &etag),
+            None => fields
+                .get("if-modified-since")
+                .and_then(date::parse)
+                .map(|x| x >= UNIX_EPOCH + std::time::Duration::from_secs(seconds))
+                .unwrap_or(false),
+        };
+        if not_modified {
+            response.status = 304;
+            return Ok((response, None));
+        }
+
+        // a stale If-Range asks for the whole file
+        let fresh = match fields.get("if-range") {
+            Some(x) => x == etag.as_bytes() || x == last_modified.as_bytes(),
+            None => true,
+        };
+        let requested = match fields.get("range") {
+            Some(x) if fresh => range(x, length),
+            _ => None,
+        };
+        let (start, end) = match requested {
+            None => (0, length),
+            Some(Ok((start, end))) => {
+                response.status = 206;
+                response = response.header(
+                    "Content-Range",
+                    &format!("bytes {}-{}/{}", start, end, length),
+                );
+                (start, end + 1)
+            }
+            Some(Err(())) => {
+                let response =
+                    Response::new(416).header("Content-Range", &format!("bytes */{}", length));
+                return Ok((response, None));
+            }
+        };
+
+        let body = Body {
+            file,
+            start,
+            length: end - start,
+        };
+        Ok((response, Some(body)))
+    }
+
+    /// Answer the request from files, extra header fields are appended to the response
+    pub async fn serve<W>(
+        &self,
+        startline: &StartLine,
+        fields: &Fields,
+        headers: Vec<(String, String)>,
+        mut stream: W,
+    ) -> Result<(), io::Error>
+    where
+        W: io::Write + marker::Unpin,
+    {
+        let (mut response, body) = self.respond(startline, fields);
+        response.headers.extend(headers);
+        let body = match body {
+            Some(x) => x,
+            None => return response.send(stream).await,
+        };
+
+        let mut writer = WriteWrapper::new(&mut stream);
+        writer.write_all(&response.head(body.length)).await?;
+        writer.flush().await?;
+        if startline.method != Method::HEAD {
+            let mut file = body.file;
+            file.seek(io::SeekFrom::Start(body.start))?;
+            // File to TcpStream copy goes through sendfile on linux
+            io::copy(&mut file.take(body.length), &mut stream)?;
+        }
+        stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn files() -> Files {
+        Files {
+            root: PathBuf::from("test/static"),
+            index: "index.html".to_string(),
+            fallback: None,
+        }
+    }
+
+    fn respond(files: &Files, target: &str, lines: &[&str]) -> (Response, Option<Body>) {
+        let startline = format!("GET {} HTTP/1.1", target);
+        let startline: StartLine = startline.as_bytes().try_into().unwrap();
+        let mut fields = Fields::new();
+        for line in lines {
+            fields.push(line.as_bytes());
+        }
+        files.respond(&startline, &fields)
+    }
+
+    fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, x)| x.as_str())
+    }
+
+    #[test]
+    fn traversal() {
+        assert_eq!(sanitize(b"/a/./b//c"), Some(PathBuf::from("a/b/c")));
+        assert_eq!(sanitize(b"/a/../../etc/passwd"), None);
+        assert_eq!(sanitize(b"/%2e%2e/etc/passwd"), None);
+        assert_eq!(sanitize(b"/a%00"), None);
+        assert_eq!(sanitize(b"/%zz"), None);
+
+        let (response, _) = respond(&files(), "/%2e%2e/Cargo.toml", &[]);
+        assert_eq!(response.status, 400);
+    }
+
+    #[test]
+    fn byte_range() {
+        assert_eq!(range(b"bytes=0-9", 100), Some(Ok((0, 9))));
+        assert_eq!(range(b"bytes=90-", 100), Some(Ok((90, 99))));
+        assert_eq!(range(b"bytes=-10", 100), Some(Ok((90, 99))));
+        assert_eq!(range(b"bytes=50-200", 100), Some(Ok((50, 99))));
+        assert_eq!(range(b"bytes=100-", 100), Some(Err(())));
+        assert_eq!(range(b"bytes=0-1,5-6", 100), None);
+        assert_eq!(range(b"items=0-1", 100), None);
+    }
+
+    #[test]
+    fn serve_file() {
+        let files = files();
+        let (response, body) = respond(&files, "/", &[]);
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            header(&response, "Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(header(&response, "Vary"), Some("Accept-Encoding"));
+        let etag = header(&response, "ETag").unwrap().to_string();
+        let length = body.unwrap().length;
+
+        let (response, _) = respond(&files, "/", &["Accept-Encoding: gzip"]);
+        assert_eq!(header(&response, "Content-Encoding"), Some("gzip"));
+        // index.html.br links outside of root
+        let (response, _) = respond(&files, "/", &["Accept-Encoding: br, gzip"]);
+        assert_eq!(header(&response, "Content-Encoding"), Some("gzip"));
+
+        let (response, body) = respond(&files, "/", &[&format!("If-None-Match: {}", etag)]);
+        assert_eq!(response.status, 304);
+        assert!(body.is_none());
+
+        let (response, body) = respond(&files, "/index.html", &["Range: bytes=1-"]);
+        assert_eq!(response.status, 206);
+        assert_eq!(body.unwrap().length, length - 1);
+
+        let (response, _) = respond(&files, "/assets?v=1", &[]);
+        assert_eq!(response.status, 301);
+        assert_eq!(header(&response, "Location"), Some("/assets/?v=1"));
+
+        let (response, _) = respond(&files, "/missing", &[]);
+        assert_eq!(response.status, 404);
+
+        let files = Files {
+            fallback: Some("/index.html".to_string()),
+            ..files
+        };
+        let (response, body) = respond(&files, "/app/settings", &[]);
+        assert_eq!(response.status, 200);
+        assert_eq!(body.unwrap().length, length);
+    }
+}
@@ -1,6 +1,5 @@
-use crate::config::prelude::hash;
 use std::borrow::Cow;
-use std::{cmp, mem};
+use std::cmp;
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
@@ -27,23 +26,23 @@ impl TryFrom<&[u8]> for ConnectionState {
     }
 }
 
+// decimal digits, as in `Content-Length: 1024`
 fn parse_numeric(input: &[u8]) -> Result<usize, Error> {
-    if input.len() > mem::size_of::<usize>() {
-        Err(Error::TooLargeValue)
-    } else {
-        let mut val: usize = 0;
-        for i in input {
-            val *= 256;
-            val += *i as usize;
-        }
-        Ok(val)
+    let input = input.trim_ascii();
+    if input.is_empty() || !input.iter().all(u8::is_ascii_digit) {
+        return Err(Error::MisMatchedValue);
     }
+    input.iter().try_fold(0_usize, |val, x| {
+        val.checked_mul(10)
+            .and_then(|val| val.checked_add((x - b'0') as usize))
+            .ok_or(Error::TooLargeValue)
+    })
 }
 
 #[derive(Debug, PartialEq)]
 pub enum Header {
     ContentLength(usize),
-    Host(u64),
+    Host(Vec<u8>),
     Unknown(Vec<u8>),
     TransferEncoding,
     Connection(ConnectionState),
@@ -75,21 +74,17 @@ impl TryFrom<Vec<u8>> for Header {
 
         value.0 = cmp::min(value.0, value.1);
 
-        let field = &input[field.0..field.1];
+        // field names are case-insensitive
+        let field = input[field.0..field.1].to_ascii_lowercase();
         let value = &input[value.0..value.1];
 
-        Ok(match field {
-            b"Transfer-Encoding" => Self::TransferEncoding,
-            b"Content-Length" => Self::ContentLength(parse_numeric(&value)?),
-            b"Host" => Self::Host(hash(value)),
-            b"Connection" => Self::Connection(value.try_into()?),
-            b"Keep-Alive" => Self::KeepAlive(parse_numeric(&value)?),
-            _ => {
-                #[cfg(debug_assertions)]
-                return Ok(Self::Unknown(input.to_owned()));
-                #[cfg(not(debug_assertions))]
-                return Ok(Self::Unknown(([0; 0]).to_vec()));
-            } //bad partice
+        Ok(match field.as_slice() {
+            b"transfer-encoding" => Self::TransferEncoding,
+            b"content-length" => Self::ContentLength(parse_numeric(&value)?),
+            b"host" => Self::Host(value.to_vec()),
+            b"connection" => Self::Connection(value.try_into()?),
+            b"keep-alive" => Self::KeepAlive(parse_numeric(&value)?),
+            _ => Self::Unknown(input),
         })
     }
 }
@@ -102,6 +97,109 @@ impl<'a> TryFrom<Cow<'a, [u8]>> for Header {
     }
 }
 
+/// Header fields without dedicated variant, kept for routing rules
+#[derive(Debug, Default, Clone)]
+pub struct Fields {
+    fields: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Fields {
+    pub fn new() -> Self {
+        Fields { fields: vec![] }
+    }
+    /// Push a raw header line(`Name: value`)
+    pub fn push(&mut self, line: &[u8]) {
+        let split = line.iter().position(|&x| x == b':').unwrap_or(line.len());
+        let (name, value) = line.split_at(split);
+        let value = value.get(1..).unwrap_or_default().trim_ascii();
+        self.fields
+            .push((name.trim_ascii().to_vec(), value.to_vec()));
+    }
+    /// Values of all fields with the name, case-insensitive
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(x, _)| x.eq_ignore_ascii_case(name.as_bytes()))
+            .map(|(_, x)| x.as_slice())
+    }
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.fields
+            .iter()
+            .find(|(x, _)| x.eq_ignore_ascii_case(name.as_bytes()))
+            .map(|(_, x)| x.as_slice())
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.fields
+            .iter()
+            .map(|(x, y)| (x.as_slice(), y.as_slice()))
+    }
+    /// Replace all fields of the name with a single one
+    pub fn set(&mut self, name: &str, value: &[u8]) {
+        self.remove(name);
+        self.fields.push((name.as_bytes().to_vec(), value.to_vec()));
+    }
+    pub fn remove(&mut self, name: &str) {
+        self.fields
+            .retain(|(x, _)| !x.eq_ignore_ascii_case(name.as_bytes()));
+    }
+    /// Serialize as header lines, each ending with CRLF
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut output = vec![];
+        for (name, value) in &self.fields {
+            output.extend_from_slice(name);
+            output.extend_from_slice(b": ");
+            output.extend_from_slice(value);
+            output.extend_from_slice(b"\r\n");
+        }
+        output
+    }
+    /// Whether the request came over https
+    ///
+    /// Without TLS listener, this relies on `X-Forwarded-Proto` set by the TLS terminator in front
+    pub fn is_secure(&self) -> bool {
+        self.get("x-forwarded-proto")
+            .map(|x| x.eq_ignore_ascii_case(b"https"))
+            .unwrap_or(false)
+    }
+    /// Whether `Accept-Encoding` allows the content coding, `q=0` refuses it
+    ///
+    /// An explicit entry of the coding takes precedence over `*`.
+    pub fn accepts(&self, coding: &str) -> bool {
+        let mut wildcard = false;
+        for item in self
+            .get_all("accept-encoding")
+            .flat_map(|x| x.split(|&x| x == b','))
+        {
+            let mut params = item.split(|&x| x == b';');
+            let name = params.next().unwrap_or_default().trim_ascii();
+            let accepted = !params.any(|x| {
+                let x = x.trim_ascii();
+                x.starts_with(b"q=") && x[2..].iter().all(|&x| x == b'0' || x == b'.')
+            });
+            if name.eq_ignore_ascii_case(coding.as_bytes()) {
+                return accepted;
+            } else if name == b"*" {
+                wildcard = accepted;
+            }
+        }
+        wildcard
+    }
+    /// Value of a cookie among all `Cookie` fields
+    pub fn cookie(&self, name: &str) -> Option<&[u8]> {
+        self.get_all("cookie")
+            .flat_map(|x| x.split(|&x| x == b';'))
+            .find_map(|pair| {
+                let pair = pair.trim_ascii();
+                let split = pair.iter().position(|&x| x == b'=')?;
+                if &pair[..split] == name.as_bytes() {
+                    Some(&pair[split + 1..])
+                } else {
+                    None
+                }
+            })
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -113,16 +211,52 @@ mod test {
 
         let binary_host = b"www.example.com";
 
-        assert_eq!(Header::Host(hash(binary_host)), result);
+        assert_eq!(Header::Host(binary_host.to_vec()), result);
+
+        let source = b"host: www.example.com".to_vec();
+        let result: Header = source.try_into().unwrap();
+        assert_eq!(Header::Host(binary_host.to_vec()), result);
+    }
+
+    #[test]
+    fn fields() {
+        let mut fields = Fields::new();
+        fields.push(b"X-Api-Version: 2");
+        fields.push(b"Cookie: a=1; beta=yes");
+        fields.push(b"cookie: c=3");
+
+        assert_eq!(fields.get("x-api-version"), Some(&b"2"[..]));
+        assert_eq!(fields.get("X-Unknown"), None);
+        assert_eq!(fields.cookie("beta"), Some(&b"yes"[..]));
+        assert_eq!(fields.cookie("c"), Some(&b"3"[..]));
+        assert_eq!(fields.cookie("b"), None);
+        assert!(!fields.is_secure());
+        assert!(!fields.accepts("gzip"));
+        fields.push(b"Accept-Encoding: gzip, deflate;q=0.5, br;q=0");
+        assert!(fields.accepts("gzip"));
+        assert!(fields.accepts("deflate"));
+        assert!(!fields.accepts("br"));
+
+        fields.set("accept-encoding", b"br");
+        fields.remove("cookie");
+        assert_eq!(
+            fields.to_bytes(),
+            b"X-Api-Version: 2\r\naccept-encoding: br\r\n"
+        );
+
+        fields.push(b"X-Forwarded-Proto: https");
+        assert!(fields.is_secure());
     }
 
     #[test]
     fn numeric_parsing() {
-        let source: &[u8] = &[1, 212, 8, 71];
+        let source: &[u8] = b"30672967";
         let result = parse_numeric(source);
         assert_eq!(30672967, result.unwrap());
+        assert_eq!(Ok(1), parse_numeric(b" 1"));
+        assert_eq!(Err(Error::MisMatchedValue), parse_numeric(b"-1"));
 
-        let source: &[u8] = &[8, 4, 1, 212, 8, 4, 1, 212, 8, 4, 1, 212, 8, 71];
+        let source: &[u8] = b"340282366920938463463374607431768211456";
         let result = parse_numeric(source);
 
         assert_eq!(Error::TooLargeValue, result.unwrap_err());
@@ -0,0 +1,253 @@
+use std::io::{self, Read, Write};
+use std::net;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use super::startline::Method;
+use super::upstream::{self, Body};
+
+// enough of a body to look for the expected text
+const MAX_BODY_SIZE: u64 = 65536;
+
+/// How an upstream is probed
+#[derive(Debug, Clone, PartialEq)]
+pub enum Probe {
+    // connection accepted
+    Tcp,
+    // `GET path` answered with a status in the range, and the text in body if any,
+    // `Host` is the address of upstream when not given
+    Http {
+        path: String,
+        host: Option<String>,
+        status: (u16, u16),
+        body: Option<String>,
+    },
+}
+
+/// Health checking of upstreams
+///
+/// An upstream is marked down after `fall` failed probes in a row, and up again
+/// after `rise` passing ones.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub probe: Probe,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub rise: usize,
+    pub fall: usize,
+}
+
+impl Default for Check {
+    fn default() -> Self {
+        Check {
+            probe: Probe::Tcp,
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+            rise: 2,
+            fall: 3,
+        }
+    }
+}
+
+impl Check {
+    /// Whether the upstream passes the probe
+    pub fn probe(&self, addr: net::SocketAddr) -> bool {
+        let stream = match net::TcpStream::connect_timeout(&addr, self.timeout) {
+            Ok(x) => x,
+            Err(_) => return false,
+        };
+        match &self.probe {
+            Probe::Tcp => true,
+            Probe::Http {
+                path,
+                host,
+                status,
+                body,
+            } => {
+                let host = host.clone().unwrap_or_else(|| addr.to_string());
+                let response = request(&stream, path, &host, self.timeout);
+                match response {
+                    Ok((x, content)) => {
+                        (status.0..=status.1).contains(&x)
+                            && body
+                                .as_ref()
+                                .is_none_or(|x| content.windows(x.len()).any(|y| y == x.as_bytes()))
+                    }
+                    Err(_) => false,
+                }
+            }
+        }
+    }
+}
+
+// status and the start of body of `GET path`
+fn request(
+    stream: &net::TcpStream,
+    path: &str,
+    host: &str,
+    timeout: Duration,
+) -> Result<(u16, Vec<u8>), io::Error> {
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nUser-Agent: health-check\r\n\r\n",
+        path, host
+    );
+    let mut writer = stream;
+    writer.write_all(request.as_bytes())?;
+
+    let (head, rest) = futures::executor::block_on(upstream::read_head(stream))?;
+    let reader = io::BufReader::new(io::Cursor::new(rest).chain(stream));
+    let mut content = vec![];
+    Body::new(reader, head.framing(&Method::GET))
+        .take(MAX_BODY_SIZE)
+        .read_to_end(&mut content)?;
+    Ok((head.status, content))
+}
+
+#[derive(Debug)]
+struct State {
+    healthy: AtomicBool,
+    // probes in a row with the other outcome than the state
+    streak: AtomicUsize,
+}
+
+/// Upstreams of a host with their health, as found out by probes in background
+#[derive(Debug)]
+pub struct Health {
+    check: Check,
+    addrs: Vec<(net::SocketAddr, State)>,
+}
+
+impl Health {
+    /// Every upstream starts healthy
+    pub fn new(check: Check, addrs: Vec<net::SocketAddr>) -> Self {
+        let addrs = addrs
+            .into_iter()
+            .map(|x| {
+                let state = State {
+                    healthy: AtomicBool::new(true),
+                    streak: AtomicUsize::new(0),
+                };
+                (x, state)
+            })
+            .collect();
+        Health { check, addrs }
+    }
+
+    /// Unknown upstreams are taken as healthy
+    pub fn healthy(&self, addr: net::SocketAddr) -> bool {
+        self.addrs
+            .iter()
+            .find(|(x, _)| *x == addr)
+            .is_none_or(|(_, x)| x.healthy.load(Ordering::Acquire))
+    }
+
+    /// Count the outcome of a probe, returning the new state when it changed
+    pub fn record(&self, addr: net::SocketAddr, passed: bool) -> Option<bool> {
+        let (_, state) = self.addrs.iter().find(|(x, _)| *x == addr)?;
+        let healthy = state.healthy.load(Ordering::Acquire);
+        if passed == healthy {
+            state.streak.store(0, Ordering::Release);
+            return None;
+        }
+        let streak = state.streak.fetch_add(1, Ordering::AcqRel) + 1;
+        let threshold = if healthy {
+            self.check.fall
+        } else {
+            self.check.rise
+        };
+        if streak < threshold {
+            return None;
+        }
+        state.streak.store(0, Ordering::Release);
+        state.healthy.store(passed, Ordering::Release);
+        Some(passed)
+    }
+
+    /// Probe each upstream in a thread of its own, for as long as the process runs
+    pub fn spawn(self: &Arc<Self>) {
+        for (addr, _) in self.addrs.iter() {
+            let addr = *addr;
+            let health = self.clone();
+            thread::spawn(move || loop {
+                let passed = health.check.probe(addr);
+                if let Some(healthy) = health.record(addr, passed) {
+                    let state = if healthy { "up" } else { "down" };
+                    println!("upstream {} is {}", addr, state);
+                }
+                thread::sleep(health.check.interval);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn transitions() {
+        let addr: net::SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        let check = Check {
+            rise: 2,
+            fall: 3,
+            ..Check::default()
+        };
+        let health = Health::new(check, vec![addr]);
+
+        assert_eq!(health.record(addr, false), None);
+        assert_eq!(health.record(addr, false), None);
+        // a pass in between starts over
+        assert_eq!(health.record(addr, true), None);
+        assert_eq!(health.record(addr, false), None);
+        assert_eq!(health.record(addr, false), None);
+        assert_eq!(health.record(addr, false), Some(false));
+        assert!(!health.healthy(addr));
+
+        assert_eq!(health.record(addr, true), None);
+        assert_eq!(health.record(addr, true), Some(true));
+        assert!(health.healthy(addr));
+        assert!(health.healthy("127.0.0.1:8001".parse().unwrap()));
+    }
+
+    #[test]
+    fn http_probe() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let responses = [
+            "HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nstatus",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n",
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 2\r\n\r\nok",
+        ];
+        let server = thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0_u8; 1024];
+                let byte_read = stream.read(&mut request).unwrap();
+                assert!(request[..byte_read].starts_with(b"GET /healthz HTTP/1.1\r\nHost: a\r\n"));
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        let check = Check {
+            probe: Probe::Http {
+                path: "/healthz".to_string(),
+                host: Some("a".to_string()),
+                status: (200, 399),
+                body: Some("ok".to_string()),
+            },
+            ..Check::default()
+        };
+        assert!(!check.probe(addr));
+        assert!(check.probe(addr));
+        assert!(!check.probe(addr));
+        server.join().unwrap();
+
+        let check = Check::default();
+        // nothing listens there any more
+        assert!(!check.probe(addr));
+    }
+}
@@ -0,0 +1,134 @@
+use std::str;
+
+use super::punycode;
+
+const MAX_HOST_LENGTH: usize = 253;
+const MAX_LABEL_LENGTH: usize = 63;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Malformed,
+}
+
+/// Normalize the value of the Host header into the form used by host lookup
+///
+/// Lowercase, without trailing dot, IDN labels encoded in punycode.
+/// The port is stripped when it is the listener port, and kept(`host:port`) otherwise.
+pub fn normalize(input: &[u8], listen_port: u16) -> Result<String, Error> {
+    let input = str::from_utf8(input).map_err(|_| Error::Malformed)?.trim();
+    if input.is_empty() {
+        return Ok(String::new());
+    }
+
+    let (name, port) = split_port(input)?;
+
+    let name = if name.starts_with('[') {
+        ipv6_literal(name)?
+    } else {
+        let name = name.strip_suffix('.').unwrap_or(name);
+        let labels = name
+            .split('.')
+            .map(label)
+            .collect::<Result<Vec<String>, Error>>()?;
+        labels.join(".")
+    };
+    if name.len() > MAX_HOST_LENGTH {
+        return Err(Error::Malformed);
+    }
+
+    Ok(match port {
+        Some(port) if port != listen_port => format!("{}:{}", name, port),
+        _ => name,
+    })
+}
+
+fn split_port(input: &str) -> Result<(&str, Option<u16>), Error> {
+    let split = if input.starts_with('[') {
+        input.find(']').ok_or(Error::Malformed)? + 1
+    } else {
+        input.find(':').unwrap_or(input.len())
+    };
+    let (name, port) = input.split_at(split);
+    match port.strip_prefix(':') {
+        Some(port) if !port.is_empty() && port.bytes().all(|x| x.is_ascii_digit()) => {
+            let port = port.parse().map_err(|_| Error::Malformed)?;
+            Ok((name, Some(port)))
+        }
+        None if port.is_empty() => Ok((name, None)),
+        _ => Err(Error::Malformed),
+    }
+}
+
+fn ipv6_literal(input: &str) -> Result<String, Error> {
+    let address = &input[1..input.len() - 1];
+    if address.is_empty()
+        || !address
+            .bytes()
+            .all(|x| x.is_ascii_hexdigit() || x == b':' || x == b'.')
+    {
+        return Err(Error::Malformed);
+    }
+    Ok(input.to_ascii_lowercase())
+}
+
+fn label(input: &str) -> Result<String, Error> {
+    let label = if input.is_ascii() {
+        input.to_ascii_lowercase()
+    } else {
+        let lowercase = input.to_lowercase();
+        let encoded = punycode::encode(&lowercase).ok_or(Error::Malformed)?;
+        format!("xn--{}", encoded)
+    };
+
+    let valid = !label.is_empty()
+        && label.len() <= MAX_LABEL_LENGTH
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+        && label
+            .bytes()
+            .all(|x| x.is_ascii_alphanumeric() || x == b'-' || x == b'_');
+    if valid {
+        Ok(label)
+    } else {
+        Err(Error::Malformed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn normalize_host() {
+        let port = 8081;
+        assert_eq!(normalize(b"A.Example.com", port).unwrap(), "a.example.com");
+        assert_eq!(normalize(b"a.example.com.", port).unwrap(), "a.example.com");
+        assert_eq!(
+            normalize(b"a.example.com:8081", port).unwrap(),
+            "a.example.com"
+        );
+        assert_eq!(
+            normalize(b"a.example.com:8080", port).unwrap(),
+            "a.example.com:8080"
+        );
+        assert_eq!(
+            normalize("Bücher.example".as_bytes(), port).unwrap(),
+            "xn--bcher-kva.example"
+        );
+        assert_eq!(normalize(b"[::1]:8081", port).unwrap(), "[::1]");
+    }
+
+    #[test]
+    fn malformed_host() {
+        let port = 8081;
+        assert_eq!(normalize(b"a..example.com", port), Err(Error::Malformed));
+        assert_eq!(normalize(b"a.example.com:", port), Err(Error::Malformed));
+        assert_eq!(
+            normalize(b"a.example.com:99999", port),
+            Err(Error::Malformed)
+        );
+        assert_eq!(normalize(b"a/b.example.com", port), Err(Error::Malformed));
+        assert_eq!(normalize(b"-a.example.com", port), Err(Error::Malformed));
+        assert_eq!(normalize(b"[::1", port), Err(Error::Malformed));
+    }
+}
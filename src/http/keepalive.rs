@@ -0,0 +1,198 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Limits of keeping connections to upstream open between requests
+///
+/// `idle` is the number of idle connections kept for each upstream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeepAlive {
+    pub idle: usize,
+    pub idle_timeout: Duration,
+    // responses on a connection before it is closed
+    pub requests: usize,
+    pub lifetime: Duration,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        KeepAlive {
+            idle: 32,
+            idle_timeout: Duration::from_secs(60),
+            requests: 1000,
+            lifetime: Duration::from_secs(3600),
+        }
+    }
+}
+
+/// Connection to upstream, with what is needed to tell when it should be closed
+#[derive(Debug)]
+pub struct Connection {
+    pub stream: net::TcpStream,
+    created: Instant,
+    requests: usize,
+}
+
+impl Connection {
+    pub fn new(stream: net::TcpStream) -> Self {
+        Connection {
+            stream,
+            created: Instant::now(),
+            requests: 0,
+        }
+    }
+
+    // closed by upstream or with unexpected data, without waiting
+    fn open(&self) -> bool {
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let peeked = self.stream.peek(&mut [0_u8; 1]);
+        let open = matches!(peeked, Err(x) if x.kind() == io::ErrorKind::WouldBlock);
+        open && self.stream.set_nonblocking(false).is_ok()
+    }
+}
+
+impl io::Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::Read::read(&mut self.stream, buf)
+    }
+}
+
+/// Idle connections to each upstream, the most recently used taken first
+#[derive(Debug)]
+pub struct Pool {
+    config: KeepAlive,
+    idle: Mutex<HashMap<net::SocketAddr, VecDeque<(Connection, Instant)>>>,
+}
+
+impl Pool {
+    pub fn new(config: KeepAlive) -> Self {
+        Pool {
+            config,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Idle connection to `addr` still open, None when there is none
+    pub fn get(&self, addr: net::SocketAddr) -> Option<Connection> {
+        let mut idle = self.idle.lock().unwrap();
+        let idle = idle.get_mut(&addr)?;
+        // the rest are older, so expired as well when this one is
+        while let Some((connection, since)) = idle.pop_back() {
+            if since.elapsed() < self.config.idle_timeout
+                && connection.created.elapsed() < self.config.lifetime
+                && connection.open()
+            {
+                return Some(connection);
+            }
+        }
+        None
+    }
+
+    /// Keep the connection for the next request to `addr`, unless it is done with
+    pub fn put(&self, addr: net::SocketAddr, mut connection: Connection) {
+        connection.requests += 1;
+        if connection.requests >= self.config.requests
+            || connection.created.elapsed() >= self.config.lifetime
+            || self.config.idle == 0
+        {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        let idle = idle.entry(addr).or_default();
+        while idle
+            .front()
+            .is_some_and(|(_, since)| since.elapsed() >= self.config.idle_timeout)
+            || idle.len() >= self.config.idle
+        {
+            idle.pop_front();
+        }
+        idle.push_back((connection, Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+    use std::thread;
+
+    // connection to the listener, with the end accepted by it
+    fn connect(listener: &net::TcpListener) -> (Connection, net::TcpStream) {
+        let stream = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (Connection::new(stream), listener.accept().unwrap().0)
+    }
+
+    fn port(connection: &Connection) -> u16 {
+        connection.stream.local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn reuse() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = Pool::new(KeepAlive {
+            idle: 2,
+            requests: 2,
+            ..KeepAlive::default()
+        });
+
+        let (first, _accepted) = connect(&listener);
+        let local = port(&first);
+        pool.put(addr, first);
+        let first = pool.get(addr).unwrap();
+        assert_eq!(port(&first), local);
+        assert!(pool.get(addr).is_none());
+        // served as many requests as allowed
+        pool.put(addr, first);
+        assert!(pool.get(addr).is_none());
+
+        // the oldest goes when more than `idle` are kept
+        let mut accepted = vec![];
+        let mut ports = vec![];
+        for _ in 0..3 {
+            let (connection, x) = connect(&listener);
+            ports.push(port(&connection));
+            accepted.push(x);
+            pool.put(addr, connection);
+        }
+        assert_eq!(port(&pool.get(addr).unwrap()), ports[2]);
+        assert_eq!(port(&pool.get(addr).unwrap()), ports[1]);
+        assert!(pool.get(addr).is_none());
+    }
+
+    #[test]
+    fn expiry() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = Pool::new(KeepAlive {
+            idle_timeout: Duration::from_millis(20),
+            ..KeepAlive::default()
+        });
+        let (connection, _accepted) = connect(&listener);
+        pool.put(addr, connection);
+        thread::sleep(Duration::from_millis(40));
+        assert!(pool.get(addr).is_none());
+
+        let pool = Pool::new(KeepAlive::default());
+        // closed by upstream while idle
+        let (connection, accepted) = connect(&listener);
+        pool.put(addr, connection);
+        drop(accepted);
+        // upstream sent something nobody asked for
+        let (connection, mut accepted) = connect(&listener);
+        pool.put(addr, connection);
+        accepted
+            .write_all(b"HTTP/1.1 408 Request Timeout\r\n\r\n")
+            .unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert!(pool.get(addr).is_none());
+
+        let (connection, _accepted) = connect(&listener);
+        pool.put(addr, connection);
+        assert!(pool.get(addr).is_some());
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// how fast the average of latency forgets, older samples count for 1/e after as long
+const DECAY: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default)]
+struct State {
+    active: AtomicUsize,
+    // moving average of latency in seconds, with when it was last updated
+    latency: Mutex<Option<(f64, Instant)>>,
+}
+
+/// Requests in flight to each upstream of a host, with how long they take
+#[derive(Debug)]
+pub struct Load {
+    addrs: Vec<(net::SocketAddr, State)>,
+}
+
+impl Load {
+    pub fn new(addrs: Vec<net::SocketAddr>) -> Self {
+        let addrs = addrs.into_iter().map(|x| (x, State::default())).collect();
+        Load { addrs }
+    }
+
+    fn state(&self, addr: net::SocketAddr) -> Option<&State> {
+        self.addrs.iter().find(|(x, _)| *x == addr).map(|(_, x)| x)
+    }
+
+    /// Requests to `addr` not done yet, zero for unknown upstreams
+    pub fn active(&self, addr: net::SocketAddr) -> usize {
+        self.state(addr)
+            .map_or(0, |x| x.active.load(Ordering::Acquire))
+    }
+
+    /// Count a request to `addr` until the returned guard is dropped
+    pub fn start(self: &Arc<Self>, addr: net::SocketAddr) -> Option<Active> {
+        let index = self.addrs.iter().position(|(x, _)| *x == addr)?;
+        self.addrs[index].1.active.fetch_add(1, Ordering::AcqRel);
+        Some(Active {
+            load: self.clone(),
+            index,
+        })
+    }
+
+    /// Add a sample of latency to the moving average of `addr`
+    ///
+    /// A slower sample is taken at once, a faster one counts for more the longer
+    /// ago the last one was, so an upstream turning slow is avoided right away.
+    pub fn record(&self, addr: net::SocketAddr, latency: Duration) {
+        let state = match self.state(addr) {
+            Some(x) => x,
+            None => return,
+        };
+        let sample = latency.as_secs_f64();
+        let mut average = state.latency.lock().unwrap();
+        let now = Instant::now();
+        let value = match *average {
+            Some((value, _)) if sample > value => sample,
+            Some((value, since)) => {
+                let kept = (-now.duration_since(since).as_secs_f64() / DECAY.as_secs_f64()).exp();
+                value * kept + sample * (1.0 - kept)
+            }
+            None => sample,
+        };
+        *average = Some((value, now));
+    }
+
+    /// Average latency of `addr` times the requests in flight with this one, for its weight
+    ///
+    /// Upstreams not measured yet cost nothing, so they get requests to be measured.
+    pub fn cost(&self, addr: net::SocketAddr, weight: i64) -> f64 {
+        let state = match self.state(addr) {
+            Some(x) => x,
+            None => return 0.0,
+        };
+        let latency = state.latency.lock().unwrap().map_or(0.0, |(x, _)| x);
+        let active = state.active.load(Ordering::Acquire);
+        latency * (active + 1) as f64 / weight as f64
+    }
+
+    /// `candidates` with their weights, the least loaded for its weight first,
+    /// equally loaded ones in random order
+    pub fn order(&self, candidates: &mut [(net::SocketAddr, i64)]) {
+        let mut keyed: Vec<_> = candidates
+            .iter()
+            .map(|&(addr, weight)| (self.active(addr) as i64, weight, random(), addr))
+            .collect();
+        keyed.sort_by(|a, b| (a.0 * b.1).cmp(&(b.0 * a.1)).then(a.2.cmp(&b.2)));
+        for (candidate, (_, weight, _, addr)) in candidates.iter_mut().zip(keyed) {
+            *candidate = (addr, weight);
+        }
+    }
+}
+
+/// Shuffle `items` in place, each order as likely
+pub fn shuffle<T>(items: &mut [T]) {
+    for i in (1..items.len()).rev() {
+        items.swap(i, (random() % (i as u64 + 1)) as usize);
+    }
+}
+
+/// Request in flight, counted until dropped
+#[derive(Debug)]
+pub struct Active {
+    load: Arc<Load>,
+    index: usize,
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.load.addrs[self.index]
+            .1
+            .active
+            .fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// keys of RandomState differ for each one made
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn least_loaded() {
+        let addrs: Vec<net::SocketAddr> = ["127.0.0.1:8000", "127.0.0.1:8001", "127.0.0.1:8002"]
+            .iter()
+            .map(|x| x.parse().unwrap())
+            .collect();
+        let load = Arc::new(Load::new(addrs.clone()));
+        let first = load.start(addrs[0]);
+        let _second = load.start(addrs[1]);
+        let _third = load.start(addrs[1]);
+        assert_eq!(load.active(addrs[1]), 2);
+        assert!(load.start("127.0.0.1:8003".parse().unwrap()).is_none());
+
+        let mut candidates: Vec<_> = addrs.iter().map(|x| (*x, 1)).collect();
+        load.order(&mut candidates);
+        assert_eq!(candidates[0].0, addrs[2]);
+        assert_eq!(candidates[2].0, addrs[1]);
+        // with three times the weight, two requests are less than one
+        let mut candidates = vec![(addrs[0], 1), (addrs[1], 3)];
+        load.order(&mut candidates);
+        assert_eq!(candidates[0].0, addrs[1]);
+        drop(first);
+        assert_eq!(load.active(addrs[0]), 0);
+
+        // ties go either way
+        let mut seen = vec![];
+        for _ in 0..64 {
+            let mut candidates = vec![(addrs[0], 1), (addrs[2], 1)];
+            load.order(&mut candidates);
+            if !seen.contains(&candidates[0].0) {
+                seen.push(candidates[0].0);
+            }
+        }
+        assert_eq!(seen.len(), 2);
+    }
+
+    #[test]
+    fn latency() {
+        let addrs: Vec<net::SocketAddr> = ["127.0.0.1:8000", "127.0.0.1:8001"]
+            .iter()
+            .map(|x| x.parse().unwrap())
+            .collect();
+        let load = Arc::new(Load::new(addrs.clone()));
+        assert_eq!(load.cost(addrs[0], 1), 0.0);
+
+        load.record(addrs[0], Duration::from_millis(100));
+        load.record(addrs[1], Duration::from_millis(300));
+        assert!((load.cost(addrs[0], 1) - 0.1).abs() < 1e-9);
+        // slower at once, faster only as time goes by
+        load.record(addrs[0], Duration::from_millis(200));
+        assert!((load.cost(addrs[0], 1) - 0.2).abs() < 1e-9);
+        load.record(addrs[0], Duration::from_millis(10));
+        assert!(load.cost(addrs[0], 1) > 0.19);
+
+        // requests in flight make it cost more, weight less
+        let _active = (load.start(addrs[1]), load.start(addrs[1]));
+        assert!((load.cost(addrs[1], 1) - 0.9).abs() < 1e-6);
+        assert!((load.cost(addrs[1], 3) - 0.3).abs() < 1e-6);
+
+        let mut items: Vec<usize> = (0..8).collect();
+        shuffle(&mut items);
+        items.sort();
+        assert_eq!(items, (0..8).collect::<Vec<_>>());
+    }
+}
@@ -1,11 +1,38 @@
+pub mod admin;
+pub mod buffer;
+pub mod cache;
+pub mod compress;
+mod date;
+pub mod file;
 pub mod header;
+pub mod health;
+pub mod host;
 pub mod http;
+pub mod keepalive;
+pub mod load;
+pub mod outlier;
+mod punycode;
 pub mod request;
+pub mod response;
+pub mod ring;
 pub mod startline;
+pub mod sticky;
+pub mod upstream;
 
 pub mod prelude {
+    pub use super::buffer;
+    pub use super::cache::{Cache, Disk, Purge};
+    pub use super::compress;
+    pub use super::file::Files;
     pub use super::header;
+    pub use super::health;
+    pub use super::keepalive;
+    pub use super::load;
+    pub use super::outlier;
     pub use super::request::*;
+    pub use super::response::Response;
+    pub use super::ring;
     pub use super::startline;
-    pub use reverse_proxy::reverse_proxy;
+    pub use super::sticky;
+    pub use reverse_proxy::{cached_proxy, reverse_proxy};
 }
@@ -0,0 +1,218 @@
+use std::net;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// When upstreams failing real traffic are taken out of rotation
+///
+/// After `failures` in a row(connection errors, timeouts, 5xx), an upstream is
+/// ejected for `ejection`, doubled for each time it is ejected again, up to `max_ejection`.
+/// At most `max_percent` of upstreams are ejected at once, but always one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Eject {
+    pub failures: usize,
+    pub ejection: Duration,
+    pub max_ejection: Duration,
+    pub max_percent: usize,
+}
+
+impl Default for Eject {
+    fn default() -> Self {
+        Eject {
+            failures: 5,
+            ejection: Duration::from_secs(30),
+            max_ejection: Duration::from_secs(300),
+            max_percent: 50,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    failures: usize,
+    // ejections since the upstream was last in rotation for long
+    ejections: u32,
+    until: Option<Instant>,
+    recovered: Option<Instant>,
+    // trial request sent once the ejection is over
+    trial: Option<Instant>,
+}
+
+impl State {
+    fn ejected(&self) -> bool {
+        self.until.is_some()
+    }
+}
+
+/// Upstreams of a host with what their responses tell of them
+#[derive(Debug)]
+pub struct Outlier {
+    eject: Eject,
+    addrs: Vec<(net::SocketAddr, Mutex<State>)>,
+}
+
+impl Outlier {
+    pub fn new(eject: Eject, addrs: Vec<net::SocketAddr>) -> Self {
+        let addrs = addrs
+            .into_iter()
+            .map(|x| (x, Mutex::new(State::default())))
+            .collect();
+        Outlier { eject, addrs }
+    }
+
+    fn state(&self, addr: net::SocketAddr) -> Option<&Mutex<State>> {
+        self.addrs.iter().find(|(x, _)| *x == addr).map(|(_, x)| x)
+    }
+
+    /// Whether a request can be sent to the upstream
+    ///
+    /// Once the ejection is over, one trial request is let through at a time,
+    /// which decides whether the upstream is back. Only `claim_trial` takes it.
+    pub fn available(&self, addr: net::SocketAddr) -> bool {
+        self.state(addr)
+            .is_none_or(|x| self.admits(&x.lock().unwrap(), Instant::now()))
+    }
+
+    /// Take the trial of an upstream whose ejection is over, for the request it is picked for
+    ///
+    /// Nothing to take for an upstream in rotation, or one with a trial under way.
+    pub fn claim_trial(&self, addr: net::SocketAddr) {
+        let mut state = match self.state(addr) {
+            Some(x) => x.lock().unwrap(),
+            None => return,
+        };
+        let now = Instant::now();
+        if state.ejected() && self.admits(&state, now) {
+            state.trial = Some(now);
+        }
+    }
+
+    // whether `state` lets a request through at `now`
+    fn admits(&self, state: &State, now: Instant) -> bool {
+        match state.until {
+            None => true,
+            Some(x) if now < x => false,
+            // a trial without outcome for as long does not hold the others up
+            _ => !state
+                .trial
+                .is_some_and(|x| now.duration_since(x) < self.eject.ejection),
+        }
+    }
+
+    /// Count the outcome of a request, returning the ejection when the upstream
+    /// is taken out, or zero when it is back
+    pub fn record(&self, addr: net::SocketAddr, passed: bool) -> Option<Duration> {
+        let mut state = self.state(addr)?.lock().unwrap();
+        if passed {
+            state.failures = 0;
+            // requests sent before the ejection do not count
+            if state.trial.take().is_some() && state.ejected() {
+                state.until = None;
+                state.recovered = Some(Instant::now());
+                return Some(Duration::ZERO);
+            }
+            return None;
+        }
+
+        state.failures += 1;
+        // a failed trial is ejected again right away
+        let trial = state.trial.take().is_some();
+        if !trial && (state.ejected() || state.failures < self.eject.failures) {
+            return None;
+        }
+        if !trial && !self.ejectable(addr) {
+            return None;
+        }
+        // in rotation for long since the last time, start over
+        let settled = state
+            .recovered
+            .is_some_and(|x| x.elapsed() > self.eject.max_ejection);
+        if settled {
+            state.ejections = 0;
+        }
+        let ejection = self
+            .eject
+            .ejection
+            .saturating_mul(1 << state.ejections.min(16))
+            .min(self.eject.max_ejection);
+        state.ejections += 1;
+        state.failures = 0;
+        state.until = Some(Instant::now() + ejection);
+        Some(ejection)
+    }
+
+    // end the ejection now, tests do not wait it out
+    #[cfg(test)]
+    pub fn expire(&self, addr: net::SocketAddr) {
+        if let Some(x) = self.state(addr) {
+            let mut state = x.lock().unwrap();
+            if state.ejected() {
+                state.until = Some(Instant::now());
+            }
+        }
+    }
+
+    // `addr` can be ejected within `max_percent`
+    fn ejectable(&self, addr: net::SocketAddr) -> bool {
+        // ones busy at the moment are taken as in rotation, rather than waiting on them
+        let ejected = self
+            .addrs
+            .iter()
+            .filter(|(x, _)| *x != addr)
+            .filter(|(_, x)| x.try_lock().is_ok_and(|x| x.ejected()))
+            .count();
+        ejected == 0 || (ejected + 1) * 100 <= self.eject.max_percent * self.addrs.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ejection() {
+        let addrs: Vec<net::SocketAddr> = ["127.0.0.1:8000", "127.0.0.1:8001", "127.0.0.1:8002"]
+            .iter()
+            .map(|x| x.parse().unwrap())
+            .collect();
+        let eject = Eject {
+            failures: 2,
+            ejection: Duration::from_secs(20),
+            ..Eject::default()
+        };
+        let outlier = Outlier::new(eject, addrs.clone());
+
+        assert_eq!(outlier.record(addrs[0], false), None);
+        assert_eq!(outlier.record(addrs[0], true), None);
+        assert_eq!(outlier.record(addrs[0], false), None);
+        assert_eq!(
+            outlier.record(addrs[0], false),
+            Some(Duration::from_secs(20))
+        );
+        assert!(!outlier.available(addrs[0]));
+        assert!(outlier.available(addrs[1]));
+
+        // no more than half of upstreams
+        outlier.record(addrs[1], false);
+        assert_eq!(outlier.record(addrs[1], false), None);
+        assert!(outlier.available(addrs[1]));
+
+        // a single trial, ejected for twice as long when it fails
+        outlier.expire(addrs[0]);
+        assert!(outlier.available(addrs[0]));
+        // looking does not take the trial
+        assert!(outlier.available(addrs[0]));
+        outlier.claim_trial(addrs[0]);
+        assert!(!outlier.available(addrs[0]));
+        assert_eq!(
+            outlier.record(addrs[0], false),
+            Some(Duration::from_secs(40))
+        );
+        assert!(!outlier.available(addrs[0]));
+        outlier.expire(addrs[0]);
+        assert!(outlier.available(addrs[0]));
+        outlier.claim_trial(addrs[0]);
+        assert_eq!(outlier.record(addrs[0], true), Some(Duration::ZERO));
+        assert!(outlier.available(addrs[0]));
+        assert!(outlier.available(addrs[0]));
+    }
+}
@@ -1,11 +1,17 @@
 use futures::AsyncWriteExt;
 
-use super::{header, http::*};
+use super::buffer::Buffered;
+use super::keepalive::{Connection, Pool};
+use super::load::Active;
+use super::upstream::{self, Framing, Head};
+use super::{header, host, http::*, startline};
 use crate::config::prelude::*;
 use crate::poll::network::{ReadWrapper, WriteWrapper};
 use futures::AsyncReadExt;
 use std::net;
-use std::{cmp, io, marker};
+use std::sync::Arc;
+use std::time::Instant;
+use std::{cmp, io, marker, thread};
 
 const CHUNK_SIZE: usize = 16384;
 
@@ -16,6 +22,9 @@ pub enum Error {
     ClientIncompatible,
     ServerIncompatible,
     BadProtocal,
+    BadHost,
+    // request body over the limit of buffering
+    TooLarge,
 }
 
 // Come with a macro
@@ -32,14 +41,56 @@ macro_rules! recover {
     };
 }
 
+/// Rebuild the request head, replacing the start line and the given header fields
+///
+/// A header field with empty value is removed.
+fn rebuild_head(
+    head: &[u8],
+    startline: Option<&startline::StartLine>,
+    headers: &[(String, String)],
+) -> Vec<u8> {
+    let mut lines = head
+        .split(|&x| x == b'\n')
+        .map(|x| x.strip_suffix(b"\r").unwrap_or(x));
+    let first_line = lines.next().unwrap_or_default();
+
+    let mut output = match startline {
+        Some(x) => x.to_bytes(),
+        None => first_line.to_vec(),
+    };
+    output.extend_from_slice(b"\r\n");
+    for line in lines.take_while(|x| !x.is_empty()) {
+        let name = line.split(|&x| x == b':').next().unwrap_or_default();
+        if headers
+            .iter()
+            .any(|(x, _)| x.as_bytes().eq_ignore_ascii_case(name))
+        {
+            continue;
+        }
+        output.extend_from_slice(line);
+        output.extend_from_slice(b"\r\n");
+    }
+    for (name, value) in headers.iter().filter(|(_, x)| !x.is_empty()) {
+        output.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+    }
+    output.extend_from_slice(b"\r\n");
+    output
+}
+
 pub struct Request<I, S>
 where
     I: io::Read + io::Write + marker::Unpin,
 {
     model: Model<I, S>,
+    startline: Option<startline::StartLine>,
+    fields: header::Fields,
     keep_alive: usize,
     content_length: usize,
-    host: u64,
+    host: Vec<u8>,
+    // header fields set by the proxy
+    headers: Vec<(String, String)>,
+    // address of client
+    peer: Option<net::IpAddr>,
 }
 
 impl<I> Request<I, stage::StartLine>
@@ -50,14 +101,18 @@ where
         let model = Model::new(stream);
         Ok(Request {
             model,
+            startline: None,
+            fields: header::Fields::new(),
             keep_alive: 2,
             content_length: 0,
-            host: 0,
+            host: vec![],
+            headers: vec![],
+            peer: None,
         })
     }
 
     pub async fn parse(mut self) -> Result<Request<I, stage::MessageBody>, Error> {
-        let _startline = recover!(self.model.next().await, Error::ClientIncompatible).unwrap();
+        let startline = recover!(self.model.next().await, Error::ClientIncompatible).unwrap();
         let mut model = self.model.skip();
 
         loop {
@@ -70,6 +125,7 @@ where
                     header::Header::Unknown(x) => {
                         #[cfg(debug_assertions)]
                         println!("{}", String::from_utf8_lossy(&x));
+                        self.fields.push(&x);
                     }
                     header::Header::TransferEncoding => {
                         return Err(Error::BadProtocal);
@@ -87,9 +143,13 @@ where
         }
         Ok(Request {
             model: model.skip(),
+            startline: Some(startline),
+            fields: self.fields,
             keep_alive: self.keep_alive,
             content_length: self.content_length,
             host: self.host,
+            headers: self.headers,
+            peer: self.peer,
         })
     }
 }
@@ -98,101 +158,795 @@ impl<I> Request<I, stage::MessageBody>
 where
     I: io::Read + io::Write + marker::Unpin,
 {
-    pub async fn send(
-        mut self,
-        config: &AppState,
-        // addr: net::SocketAddr,
-    ) -> Result<net::TcpStream, Error> {
+    /// Normalized host of the request
+    pub fn host(&self, config: &AppState) -> Result<String, Error> {
+        host::normalize(&self.host, config.port).map_err(|_| Error::BadHost)
+    }
+
+    /// Lookup the route taken by the request
+    pub fn route<'a>(&self, config: &'a AppState) -> Result<(&'a Route, Captures), Error> {
+        let host = self.host(config)?;
+        match config.route(&host, self.startline(), &self.fields) {
+            Some(x) => Ok(x),
+            None => Err(Error::ClientIncompatible),
+        }
+    }
+
+    pub fn startline(&self) -> &startline::StartLine {
+        self.startline.as_ref().unwrap()
+    }
+
+    pub fn fields(&self) -> &header::Fields {
+        &self.fields
+    }
+
+    /// Set a header field on the upstream request, an empty value removes it
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.retain(|(x, _)| !x.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.fields.is_secure()
+    }
+
+    /// Set the address of client, which the request has no way to know from its stream
+    pub fn set_peer(&mut self, addr: net::IpAddr) {
+        self.peer = Some(addr);
+    }
+
+    /// Drop `X-Forwarded-Proto` of a client other than a trusted proxy, upstream included
+    ///
+    /// Anyone can send the header, a plain http client would pass for https with it.
+    pub fn distrust_forwarded(&mut self, config: &AppState) {
+        if !self.peer.is_some_and(|x| config.trusted(x)) {
+            self.fields.remove("x-forwarded-proto");
+            self.set_header("X-Forwarded-Proto", "");
+        }
+    }
+
+    /// Forward the request to upstream, returning its final response
+    ///
+    /// Interim responses go to `server` as they are. Failures are tried again
+    /// as `Retry` of the proxy allows, each time on another upstream when there is one.
+    /// With `buffer-request`, the whole body is read from client before any upstream is picked.
+    pub async fn send<W>(
+        self,
+        proxy: &Proxy,
+        captures: &Captures,
+        mut server: W,
+    ) -> Result<Upstream, Error>
+    where
+        W: io::Write + marker::Unpin,
+    {
         let (reader, read_buffer, unread_buffer) = self.model.into_parts();
 
         let mut reader = ReadWrapper::new(reader);
 
-        let addr = match config.route(self.host) {
-            Some(x) => x,
-            None => {
-                return Err(Error::ClientIncompatible);
+        let startline = self.startline.as_ref().unwrap();
+        let rewritten = proxy.rewrite(startline, captures);
+        let mut headers = proxy.headers(captures);
+        headers.extend(self.headers);
+        let head = if rewritten.is_none() && headers.is_empty() {
+            read_buffer
+        } else {
+            rebuild_head(&read_buffer, rewritten.as_ref(), &headers)
+        };
+
+        let retry = proxy.retry();
+        let buffered = match proxy.buffer_request() {
+            Some(limit) => {
+                if self.content_length as u64 > limit.max {
+                    return Err(Error::TooLarge);
+                }
+                let mut body = WriteWrapper::new(Buffered::new(limit));
+                let unread = (&unread_buffer[..], self.content_length);
+                forward_body(unread, &mut reader, &mut body).await?;
+                Some(
+                    body.into_parts()
+                        .await
+                        .map_err(|_| Error::ServerIncompatible)?,
+                )
             }
+            // the whole body came with the head, so the request can be sent again
+            None if startline.method.idempotent() && self.content_length <= unread_buffer.len() => {
+                let body = unread_buffer[..self.content_length].to_vec();
+                Some(Buffered::memory(body))
+            }
+            None => None,
         };
+        let replayable = buffered.is_some();
+        let key = proxy.hash_key(startline, &self.fields, self.peer);
+        let pinned = proxy.pinned(&self.fields);
+        let mut tried = vec![];
+        loop {
+            if !tried.is_empty() {
+                let exponent = (tried.len() - 1).min(16) as u32;
+                thread::sleep(retry.backoff.saturating_mul(1 << exponent));
+            }
+            let last = tried.len() >= retry.count;
+            let addr = match pinned.filter(|_| tried.is_empty()) {
+                Some(x) => x,
+                None => proxy.next_upstream(&tried, key.as_deref()),
+            };
+            tried.push(addr);
+            let active = proxy.track(addr);
+            let start = Instant::now();
 
-        let mut remaining_byte = self.content_length;
-        let upstream = recover!(net::TcpStream::connect(addr), Error::ServerIncompatible);
-        let mut writer = WriteWrapper::new(io::BufWriter::new(recover!(
-            upstream.try_clone(),
-            Error::ServerIncompatible
-        )));
+            // a streamed body can not be sent again when an idle connection turns out closed
+            let pooled = proxy
+                .pool()
+                .filter(|_| replayable)
+                .and_then(|x| x.get(addr));
+            let reused = pooled.is_some();
+            let upstream = match pooled {
+                Some(x) => Ok(x),
+                None => match retry.timeout {
+                    Some(x) => net::TcpStream::connect_timeout(&addr, x),
+                    None => net::TcpStream::connect(addr),
+                }
+                .map(Connection::new),
+            };
+            // every upstream down is not a bug to recover from
+            if upstream.is_err() {
+                proxy.report(addr, false, start.elapsed());
+            }
+            let upstream = match upstream {
+                Ok(x) => x,
+                Err(_) if !last => continue,
+                Err(_) => return Err(Error::ServerIncompatible),
+            };
+            upstream.stream.set_read_timeout(retry.timeout).ok();
+            upstream.stream.set_write_timeout(retry.timeout).ok();
 
-        recover!(writer.write(&read_buffer).await, Error::ServerIncompatible);
+            let sent = match &buffered {
+                Some(body) => body
+                    .send(&head, &upstream.stream)
+                    .map_err(|_| Error::ServerIncompatible),
+                None => {
+                    let unread = (&unread_buffer[..], self.content_length);
+                    stream(&upstream.stream, &head, unread, &mut reader).await
+                }
+            };
+            let response = match sent {
+                Ok(()) => {
+                    let mut reader = io::BufReader::new(upstream);
+                    final_head(&mut reader, &mut server)
+                        .await
+                        .map(|(head, rest)| (head, rest, reader))
+                }
+                Err(err) => Err(err),
+            };
 
-        let byte_sent = writer
-            .write(&unread_buffer[0..remaining_byte])
+            let retryable = replayable && !last;
+            match &response {
+                // closed while idle, not the fault of upstream
+                Err(_) if reused => {
+                    tried.pop();
+                    continue;
+                }
+                Ok((head, _, _)) => proxy.report(addr, head.status < 500, start.elapsed()),
+                Err(_) => proxy.report(addr, false, start.elapsed()),
+            }
+            match response {
+                Ok((head, _, _)) if retryable && retry.statuses.contains(&head.status) => continue,
+                Ok((mut head, rest, reader)) => {
+                    if let Some(x) = proxy.pin(addr, &self.fields) {
+                        head.fields.push(&x);
+                    }
+                    reader.get_ref().stream.set_read_timeout(None).ok();
+                    reader.get_ref().stream.set_write_timeout(None).ok();
+                    let framing = head.framing(&startline.method);
+                    let reusable =
+                        head.keep_alive() && head.status != 101 && framing != Framing::Close;
+                    let pool = proxy.pool().filter(|_| reusable).map(|x| (x.clone(), addr));
+                    return Ok(Upstream {
+                        head,
+                        reader,
+                        rest,
+                        pool,
+                        active,
+                    });
+                }
+                Err(_) if retryable => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Send the head, then the body as it comes from client, which can be done only once
+async fn stream<R>(
+    upstream: &net::TcpStream,
+    head: &[u8],
+    unread: (&[u8], usize),
+    reader: &mut ReadWrapper<R>,
+) -> Result<(), Error>
+where
+    R: io::Read + marker::Unpin,
+{
+    let mut writer = WriteWrapper::new(io::BufWriter::new(upstream));
+    writer
+        .write_all(head)
+        .await
+        .map_err(|_| Error::ServerIncompatible)?;
+    forward_body(unread, reader, &mut writer).await?;
+    writer.flush().await.map_err(|_| Error::ServerIncompatible)
+}
+
+/// Pass the body from client on to `writer`, starting with the part read along with the head
+async fn forward_body<R, W>(
+    (unread_buffer, content_length): (&[u8], usize),
+    reader: &mut ReadWrapper<R>,
+    writer: &mut WriteWrapper<W>,
+) -> Result<(), Error>
+where
+    R: io::Read + marker::Unpin,
+    W: io::Write + marker::Unpin,
+{
+    let read_ahead = cmp::min(content_length, unread_buffer.len());
+    writer
+        .write_all(&unread_buffer[..read_ahead])
+        .await
+        .map_err(|_| Error::ServerIncompatible)?;
+
+    let mut remaining_byte = content_length - read_ahead;
+    let mut chunk = [0_u8; CHUNK_SIZE];
+    while remaining_byte > 0 {
+        let plan_to_read = cmp::min(remaining_byte, CHUNK_SIZE);
+        let byte_read = reader
+            .read(&mut chunk[..plan_to_read])
+            .await
+            .map_err(|_| Error::ClientIncompatible)?;
+        // client gone before the whole body
+        if byte_read == 0 {
+            return Err(Error::ClientIncompatible);
+        }
+        writer
+            .write_all(&chunk[..byte_read])
             .await
             .map_err(|_| Error::ServerIncompatible)?;
+        remaining_byte -= byte_read;
+    }
+    Ok(())
+}
 
-        remaining_byte -= byte_sent;
+/// Final response head from upstream, with the connection its body comes from
+pub struct Upstream {
+    pub head: Head,
+    reader: io::BufReader<Connection>,
+    // read past the head
+    rest: Vec<u8>,
+    // where the connection goes back, None when it can not be kept open
+    pool: Option<(Arc<Pool>, net::SocketAddr)>,
+    active: Option<Active>,
+}
 
-        let mut chunk = [0_u8; CHUNK_SIZE];
+impl Upstream {
+    /// Head, and the connection starting with the body
+    pub fn into_parts(self) -> (Head, Reader) {
+        let reader = Reader {
+            inner: io::Read::chain(io::Cursor::new(self.rest), self.reader),
+            pool: self.pool,
+            _active: self.active,
+        };
+        (self.head, reader)
+    }
+}
 
-        loop {
-            if remaining_byte == 0 {
-                break;
-            }
+/// Connection to upstream starting with the response body
+pub struct Reader {
+    inner: io::Chain<io::Cursor<Vec<u8>>, io::BufReader<Connection>>,
+    pool: Option<(Arc<Pool>, net::SocketAddr)>,
+    // in flight until the response is done with
+    _active: Option<Active>,
+}
+
+impl Reader {
+    /// Give the connection back to the pool, once the response was read to the end
+    pub fn release(self) {
+        let (pool, addr) = match self.pool {
+            Some(x) => x,
+            None => return,
+        };
+        let (rest, reader) = self.inner.into_inner();
+        // anything left is not part of the response
+        let left = rest.get_ref().len() as u64 - rest.position();
+        if left == 0 && reader.buffer().is_empty() {
+            pool.put(addr, reader.into_inner());
+        }
+    }
+}
 
-            let plan_to_read = cmp::min(remaining_byte, CHUNK_SIZE);
+impl io::Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
 
-            let byte_read: usize = recover!(
-                reader.read(&mut chunk[0..plan_to_read]).await,
-                Error::ServerIncompatible
-            );
+impl io::BufRead for Reader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+    fn consume(&mut self, amount: usize) {
+        self.inner.consume(amount)
+    }
+}
 
-            self.content_length -= plan_to_read;
+/// Read the final response head from upstream, interim responses go to client as they are
+async fn final_head<R, W>(reader: &mut R, server: W) -> Result<(Head, Vec<u8>), Error>
+where
+    R: io::Read + marker::Unpin,
+    W: io::Write + marker::Unpin,
+{
+    let mut writer = WriteWrapper::new(server);
+    let (mut head, mut rest) = upstream::read_head(&mut *reader)
+        .await
+        .map_err(|_| Error::ServerIncompatible)?;
+    while (100..200).contains(&head.status) && head.status != 101 {
+        writer
+            .write_all(&head.to_bytes())
+            .await
+            .map_err(|_| Error::ClientIncompatible)?;
+        (head, rest) = upstream::read_head(io::Read::chain(io::Cursor::new(rest), &mut *reader))
+            .await
+            .map_err(|_| Error::ServerIncompatible)?;
+    }
+    Ok((head, rest))
+}
 
-            recover!(
-                writer.write(&chunk[0..byte_read]).await,
-                Error::ServerIncompatible
-            );
-        }
+#[cfg(test)]
+mod test {
+    use super::*;
 
-        Ok(upstream)
+    #[test]
+    fn head_rebuilding() {
+        let head = b"GET /a HTTP/1.1\r\nHost: a.example.com\r\nx-tenant: old\r\n\r\n";
+        let startline: startline::StartLine = b"GET /b HTTP/1.1".as_ref().try_into().unwrap();
+        let headers = vec![("X-Tenant".to_string(), "abc".to_string())];
+        assert_eq!(
+            rebuild_head(head, Some(&startline), &headers),
+            b"GET /b HTTP/1.1\r\nHost: a.example.com\r\nX-Tenant: abc\r\n\r\n"
+        );
     }
 }
 
 pub mod reverse_proxy {
-    use std::io;
+    use std::fs;
+    use std::io::{self, Read};
+    use std::sync::Arc;
 
     use futures::AsyncReadExt;
 
+    use super::super::buffer::{Buffer, Buffered};
+    use super::super::cache::{Cache, Content, Entry, Lookup};
+    use super::super::compress::{self, Compression};
+    use super::super::upstream::{Body, Framing};
     use super::*;
-    pub async fn reverse_proxy(
-        client: net::TcpStream,
-        server: net::TcpStream,
+
+    // a closed connection on either side ends the response quietly
+    fn closed(err: io::Error) -> Result<(), Error> {
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof => Ok(()),
+            _ => Err(Error::ServerIncompatible),
+        }
+    }
+
+    // the connection to upstream is kept, when the body was read to the end
+    fn release(body: Body<Reader>) {
+        if let Some(reader) = body.finish() {
+            reader.release();
+        }
+    }
+
+    /// Serialize the head, with extra header fields after the status line
+    fn head_bytes(head: &Head, headers: &[(String, String)]) -> Vec<u8> {
+        let mut output = head.status_line();
+        output.extend_from_slice(b"\r\n");
+        for (name, value) in headers {
+            output.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        output.extend_from_slice(&head.fields.to_bytes());
+        output.extend_from_slice(b"\r\n");
+        output
+    }
+
+    /// Send a response to client, `body` is without transfer coding
+    ///
+    /// The body is compressed when `compression` allows it, and chunked again
+    /// when it came chunked.
+    async fn deliver<R>(
+        mut head: Head,
+        body: &mut R,
+        framing: Framing,
+        server: &net::TcpStream,
+        headers: &[(String, String)],
+        compression: Option<Compression<'_>>,
+    ) -> Result<(), Error>
+    where
+        R: io::Read,
+    {
+        let coding = compression.and_then(|x| x.apply(&mut head));
+        let mut writer = WriteWrapper::new(server);
+        writer
+            .write_all(&head_bytes(&head, headers))
+            .await
+            .map_err(|_| Error::ClientIncompatible)?;
+
+        let writer = io::BufWriter::with_capacity(CHUNK_SIZE, server);
+        match (coding, framing) {
+            (Some(coding), _) => return compress::compress(body, coding, writer).or_else(closed),
+            (None, Framing::Chunked) => {
+                let mut chunked = compress::Chunked::new(writer);
+                io::copy(body, &mut chunked)
+                    .and_then(|_| chunked.finish())
+                    .map(|_| ())
+                    .or_else(closed)?;
+            }
+            (None, _) => {
+                // straight to the socket, so a file is sent with sendfile
+                let mut server = server;
+                io::copy(body, &mut server).map(|_| ()).or_else(closed)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Answer with a stored response
+    async fn deliver_entry(
+        entry: &Entry,
+        server: &net::TcpStream,
+        headers: &[(String, String)],
+        compression: Option<Compression<'_>>,
     ) -> Result<(), Error> {
-        let mut writer = WriteWrapper::new(io::BufWriter::new(server));
-        let mut reader = ReadWrapper::new(io::BufReader::new(client));
+        let framing = Framing::Length(entry.content.length());
+        match &entry.content {
+            Content::Memory(x) => {
+                let mut body = x.as_slice();
+                deliver(
+                    entry.head(),
+                    &mut body,
+                    framing,
+                    server,
+                    headers,
+                    compression,
+                )
+                .await
+            }
+            Content::File(path, length) => {
+                let file = fs::File::open(path).map_err(|_| Error::ServerIncompatible)?;
+                let mut body = file.take(*length);
+                deliver(
+                    entry.head(),
+                    &mut body,
+                    framing,
+                    server,
+                    headers,
+                    compression,
+                )
+                .await
+            }
+        }
+    }
 
-        let buffer = &mut [0_u8; CHUNK_SIZE];
-        loop {
-            let byte_read = match reader.read(buffer).await {
-                Ok(x) => x,
-                Err(err) => match err.kind() {
-                    io::ErrorKind::ConnectionRefused
-                    | io::ErrorKind::ConnectionReset
-                    | io::ErrorKind::BrokenPipe
-                    | io::ErrorKind::UnexpectedEof => {
-                        return Ok(());
-                    }
-                    _ => unreachable!(),
-                },
+    /// Read the whole body at the pace of upstream, releasing it before client gets the response
+    ///
+    /// A body larger than `limit` allows is sent on as it comes after what was read.
+    async fn deliver_buffered(
+        mut head: Head,
+        mut body: Body<Reader>,
+        framing: Framing,
+        limit: &Buffer,
+        server: &net::TcpStream,
+        headers: &[(String, String)],
+        compression: Option<Compression<'_>>,
+    ) -> Result<(), Error> {
+        let mut buffered = Buffered::new(limit);
+        let mut next = [0_u8; 1];
+        let byte_read = io::copy(&mut (&mut body).take(limit.max), &mut buffered)
+            .and_then(|_| body.read(&mut next))
+            .map_err(|_| Error::ServerIncompatible)?;
+        let file = buffered.file().map_err(|_| Error::ServerIncompatible)?;
+        if byte_read > 0 {
+            let read: Box<dyn io::Read + Send> = match file {
+                Some(x) => Box::new(x),
+                None => Box::new(buffered.in_memory()),
             };
-            if byte_read == 0 {
-                break;
+            let mut body = read.chain(&next[..]).chain(body);
+            return deliver(head, &mut body, framing, server, headers, compression).await;
+        }
+        release(body);
+
+        // the length is known now
+        let length = buffered.length();
+        if matches!(framing, Framing::Chunked | Framing::Close) {
+            head.fields.remove("transfer-encoding");
+            head.fields
+                .set("Content-Length", length.to_string().as_bytes());
+        }
+        let framing = match framing {
+            Framing::Empty => Framing::Empty,
+            _ => Framing::Length(length),
+        };
+        match file {
+            Some(mut file) => deliver(head, &mut file, framing, server, headers, compression).await,
+            None => {
+                let mut body = buffered.in_memory();
+                deliver(head, &mut body, framing, server, headers, compression).await
             }
+        }
+    }
+
+    /// Copy the response to client, with extra header fields after the status line
+    ///
+    /// The body is compressed when `compression` allows it, otherwise copied as is.
+    /// With `buffer`, the whole body is read before any of it goes to client.
+    pub async fn reverse_proxy(
+        upstream: Upstream,
+        server: &net::TcpStream,
+        headers: &[(String, String)],
+        method: &startline::Method,
+        compression: Option<Compression<'_>>,
+        buffer: Option<&Buffer>,
+    ) -> Result<(), Error> {
+        let (head, reader) = upstream.into_parts();
 
+        if head.status == 101 {
+            // upgraded connection, copy whatever comes until closed
+            let mut writer = WriteWrapper::new(io::BufWriter::new(server));
             writer
-                .write(&buffer[0..byte_read])
+                .write_all(&head_bytes(&head, headers))
                 .await
                 .map_err(|_| Error::ClientIncompatible)?;
+            let mut reader = ReadWrapper::new(reader);
+            let buffer = &mut [0_u8; CHUNK_SIZE];
+            loop {
+                let byte_read = match reader.read(buffer).await {
+                    Ok(x) => x,
+                    Err(err) => return closed(err),
+                };
+                if byte_read == 0 {
+                    break;
+                }
+
+                writer
+                    .write_all(&buffer[0..byte_read])
+                    .await
+                    .map_err(|_| Error::ClientIncompatible)?;
+                writer
+                    .flush()
+                    .await
+                    .map_err(|_| Error::ClientIncompatible)?;
+            }
+            return Ok(());
+        }
+
+        let framing = head.framing(method);
+        let mut body = Body::new(reader, framing);
+        match buffer {
+            Some(limit) => {
+                deliver_buffered(head, body, framing, limit, server, headers, compression).await
+            }
+            None => {
+                deliver(head, &mut body, framing, server, headers, compression).await?;
+                release(body);
+                Ok(())
+            }
+        }
+    }
+
+    /// Body read while storing a response
+    enum Stored {
+        Entry(Arc<Entry>),
+        // what was read of a body not stored, the rest is left in the reader
+        Read(Box<dyn io::Read + Send>),
+    }
+
+    /// Read the body into memory, or into the disk tier when too large for memory
+    fn store<R>(
+        cache: &Cache,
+        key: &str,
+        fields: &header::Fields,
+        head: &Head,
+        body: &mut R,
+    ) -> Result<Stored, Error>
+    where
+        R: io::Read,
+    {
+        let limit = cache.max_object();
+        let mut buffer = vec![];
+        body.take(limit + 1)
+            .read_to_end(&mut buffer)
+            .map_err(|_| Error::ServerIncompatible)?;
+        if buffer.len() as u64 <= limit {
+            return Ok(
+                match cache.insert(key, fields, head.clone(), buffer.clone()) {
+                    Some(entry) => Stored::Entry(entry),
+                    None => Stored::Read(Box::new(io::Cursor::new(buffer))),
+                },
+            );
+        }
+
+        // read past the limit of memory, continue on disk
+        let mut spool = match cache.spool() {
+            Some(x) => x,
+            None => return Ok(Stored::Read(Box::new(io::Cursor::new(buffer)))),
+        };
+        let disk_limit = cache.disk_max_object().unwrap_or(limit);
+        io::Write::write_all(&mut spool, &buffer)
+            .and_then(|_| {
+                io::copy(
+                    &mut body.take(disk_limit + 1 - buffer.len() as u64),
+                    &mut spool,
+                )
+            })
+            .map_err(|_| Error::ServerIncompatible)?;
+        let reader = spool.reader().map_err(|_| Error::ServerIncompatible)?;
+        if spool.length() <= disk_limit {
+            if let Some(entry) = cache.insert_spool(key, fields, head.clone(), spool) {
+                return Ok(Stored::Entry(entry));
+            }
         }
+        Ok(Stored::Read(Box::new(reader)))
+    }
 
+    /// Answer with a stale entry, `warning` tells client why
+    async fn deliver_stale(
+        entry: &Entry,
+        server: &net::TcpStream,
+        headers: &[(String, String)],
+        compression: Option<Compression<'_>>,
+        warning: &str,
+    ) -> Result<(), Error> {
+        let mut headers = headers.to_vec();
+        headers.push(("Warning".to_string(), warning.to_string()));
+        deliver_entry(entry, server, &headers, compression).await
+    }
+
+    /// Refresh a stale entry, the response only goes to cache
+    async fn revalidate<I>(
+        mut request: Request<I, stage::MessageBody>,
+        proxy: &Proxy,
+        captures: &Captures,
+        cache: &Cache,
+        key: &str,
+        entry: &Entry,
+    ) -> Result<(), Error>
+    where
+        I: io::Read + io::Write + marker::Unpin,
+    {
+        let fields = request.fields().clone();
+        for (name, value) in entry.validators() {
+            request.set_header(&name, &value);
+        }
+        let method = request.startline().method.clone();
+        let upstream = request.send(proxy, captures, io::sink()).await?;
+        let (head, reader) = upstream.into_parts();
+        let mut body = Body::new(reader, head.framing(&method));
+        if head.status == 304 {
+            cache.refresh(key, &fields, entry, &head);
+        } else if Cache::storable(&head) {
+            store(cache, key, &fields, &head, &mut body)?;
+        }
+        release(body);
         Ok(())
     }
+
+    /// Answer from cache when fresh, otherwise forward the request and store the response
+    ///
+    /// A stale response is revalidated with a conditional request. Within
+    /// `stale-while-revalidate` it is served first, then the write side of the client
+    /// is shut down and the entry refreshed inline, holding this worker until upstream
+    /// answers. Within `stale-if-error` it answers when upstream is down or fails with 5xx.
+    /// Requests missing the same key while it is fetched wait for the fetch and
+    /// answer from cache, blocking their thread up to the coalesce timeout.
+    pub async fn cached_proxy<I>(
+        mut request: Request<I, stage::MessageBody>,
+        proxy: &Proxy,
+        captures: &Captures,
+        cache: &Cache,
+        key: &str,
+        server: &net::TcpStream,
+        headers: &[(String, String)],
+    ) -> Result<(), Error>
+    where
+        I: io::Read + io::Write + marker::Unpin,
+    {
+        let fields = request.fields().clone();
+        let compression = proxy.compression(request.startline(), &fields);
+
+        let mut lookup = cache.lookup(key, &fields);
+        let mut fetch = None;
+        if matches!(lookup, Lookup::Stale(_) | Lookup::Miss) {
+            // the same fetch in flight likely stores what this request needs
+            fetch = cache.fetch_claim(key);
+            if fetch.is_none() && cache.wait(key) {
+                lookup = cache.lookup(key, &fields);
+            }
+        }
+
+        let stale = match lookup {
+            Lookup::Fresh(entry) => {
+                return deliver_entry(&entry, server, headers, compression).await;
+            }
+            Lookup::Revalidate(entry) => {
+                let warning = "110 - \"Response is Stale\"";
+                deliver_stale(&entry, server, headers, compression, warning).await?;
+                // client has the whole response, the refresh goes on without it
+                server.shutdown(net::Shutdown::Write).ok();
+                if let Some(_fetch) = cache.fetch_claim(key) {
+                    revalidate(request, proxy, captures, cache, key, &entry).await?;
+                }
+                return Ok(());
+            }
+            Lookup::Stale(entry) => {
+                for (name, value) in entry.validators() {
+                    request.set_header(&name, &value);
+                }
+                Some(entry)
+            }
+            Lookup::Miss => None,
+        };
+        let fallback = stale.as_ref().filter(|x| cache.usable_on_error(x));
+
+        let method = request.startline().method.clone();
+        let response = request.send(proxy, captures, server).await;
+        if let Some(entry) = fallback {
+            let failed = match &response {
+                Ok(x) => [500, 502, 503, 504].contains(&x.head.status),
+                Err(_) => true,
+            };
+            if failed {
+                let warning = "111 - \"Revalidation Failed\"";
+                return deliver_stale(entry, server, headers, compression, warning).await;
+            }
+        }
+        let (head, reader) = response?.into_parts();
+        let framing = head.framing(&method);
+        let mut body = Body::new(reader, framing);
+
+        if let (Some(entry), 304) = (&stale, head.status) {
+            let entry = cache
+                .refresh(key, &fields, entry, &head)
+                .unwrap_or_else(|| Arc::clone(entry));
+            drop(fetch);
+            release(body);
+            return deliver_entry(&entry, server, headers, compression).await;
+        }
+
+        let too_large = matches!(framing, Framing::Length(x)
+            if x > cache.disk_max_object().unwrap_or(cache.max_object()));
+        if head.status == 101 || too_large || !Cache::storable(&head) {
+            // nothing to be stored, waiters go on their own
+            drop(fetch);
+            return match proxy.buffer_response() {
+                Some(limit) if head.status != 101 => {
+                    deliver_buffered(head, body, framing, limit, server, headers, compression).await
+                }
+                _ => {
+                    deliver(head, &mut body, framing, server, headers, compression).await?;
+                    release(body);
+                    Ok(())
+                }
+            };
+        }
+
+        let stored = store(cache, key, &fields, &head, &mut body);
+        drop(fetch);
+        match stored? {
+            Stored::Entry(entry) => {
+                // the whole body was read, upstream is not needed any more
+                release(body);
+                deliver_entry(&entry, server, headers, compression).await
+            }
+            Stored::Read(read) => {
+                // not stored after all, send what was read and the rest
+                let mut body = read.chain(body);
+                deliver(head, &mut body, framing, server, headers, compression).await
+            }
+        }
+    }
 }
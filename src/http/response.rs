@@ -0,0 +1,100 @@
+use futures::AsyncWriteExt;
+use std::{io, marker};
+
+use crate::poll::network::WriteWrapper;
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        206 => "Partial Content",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        416 => "Range Not Satisfiable",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+/// Response generated by the proxy itself
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Response {
+            status,
+            headers: vec![],
+            body: vec![],
+        }
+    }
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+    pub fn body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+    /// Serialize status line and header fields, for a body sent separately
+    ///
+    /// `304` and `204` carry no `Content-Length`.
+    pub fn head(&self, content_length: u64) -> Vec<u8> {
+        let mut output =
+            format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status)).into_bytes();
+        for (name, value) in &self.headers {
+            output.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        if self.status != 304 && self.status != 204 {
+            output.extend_from_slice(format!("Content-Length: {}\r\n", content_length).as_bytes());
+        }
+        output.extend_from_slice(b"Connection: close\r\n\r\n");
+        output
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut output = self.head(self.body.len() as u64);
+        output.extend_from_slice(&self.body);
+        output
+    }
+    pub async fn send<W>(&self, stream: W) -> Result<(), io::Error>
+    where
+        W: io::Write + marker::Unpin,
+    {
+        let mut writer = WriteWrapper::new(stream);
+        writer.write_all(&self.to_bytes()).await?;
+        writer.flush().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[object::test]
+    async fn serialize() {
+        let response = Response::new(200)
+            .header("Content-Type", "text/plain")
+            .body(b"ok".to_vec());
+        let mut output = vec![];
+        response.send(&mut output).await.unwrap();
+        assert_eq!(
+            output,
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use std::net;
+
+// points on the ring for each upstream, on average
+const POINTS: usize = 160;
+
+/// FNV-1a, with the bits mixed so that close inputs land far apart on the ring
+///
+/// Unlike the hashers of std, it is the same for every process, so proxies
+/// in front of the same upstreams agree on where a key goes.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &x in bytes {
+        hash ^= x as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    // finalizer of splitmix64
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58476d1ce4e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+/// Consistent hashing ring of upstreams, as in ketama
+///
+/// Each upstream takes points on the ring in proportion to its weight, a key goes
+/// to the first upstream clockwise from its hash. Adding or removing an upstream
+/// only moves the keys that land on its points.
+#[derive(Debug, Clone, Default)]
+pub struct Ring {
+    points: Vec<(u64, net::SocketAddr)>,
+}
+
+impl Ring {
+    pub fn new(upstreams: &[(net::SocketAddr, i64)]) -> Self {
+        // shared out by weight, the ring stays the same size however large they are
+        let total: i128 = upstreams.iter().map(|x| x.1 as i128).sum();
+        let mut points = vec![];
+        for &(addr, weight) in upstreams {
+            let share = (POINTS * upstreams.len()) as i128 * weight as i128 / total.max(1);
+            for i in 0..share.max(1) {
+                points.push((hash(format!("{}-{}", addr, i).as_bytes()), addr));
+            }
+        }
+        points.sort();
+        Ring { points }
+    }
+
+    /// Upstreams in the order met clockwise from the key, each once
+    pub fn walk<'a>(&'a self, key: &[u8]) -> impl Iterator<Item = net::SocketAddr> + 'a {
+        let key = hash(key);
+        let start = self.points.partition_point(|(x, _)| *x < key);
+        let mut seen = vec![];
+        (0..self.points.len())
+            .map(move |i| self.points[(start + i) % self.points.len()].1)
+            .filter(move |x| {
+                if seen.contains(x) {
+                    return false;
+                }
+                seen.push(*x);
+                true
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn remapping() {
+        let addrs: Vec<net::SocketAddr> = (0..4)
+            .map(|x| format!("127.0.0.1:{}", 8000 + x).parse().unwrap())
+            .collect();
+        let upstreams: Vec<_> = addrs.iter().map(|x| (*x, 1)).collect();
+        let ring = Ring::new(&upstreams);
+        let keys: Vec<String> = (0..1000)
+            .map(|x| format!("10.0.{}.{}", x / 256, x % 256))
+            .collect();
+        let before: Vec<_> = keys
+            .iter()
+            .map(|x| ring.walk(x.as_bytes()).next().unwrap())
+            .collect();
+
+        // every upstream takes a fair share
+        for addr in addrs.iter() {
+            let count = before.iter().filter(|x| *x == addr).count();
+            assert!((150..350).contains(&count), "{} keys on {}", count, addr);
+        }
+        let walked: Vec<_> = ring.walk(b"key").collect();
+        assert_eq!(walked.len(), 4);
+
+        // only the keys of the upstream gone move, the others stay
+        let ring = Ring::new(&upstreams[..3]);
+        for (key, addr) in keys.iter().zip(before.iter()) {
+            let after = ring.walk(key.as_bytes()).next().unwrap();
+            if *addr != addrs[3] {
+                assert_eq!(after, *addr);
+            }
+        }
+    }
+
+    #[test]
+    fn weight_share() {
+        let a: net::SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        let b: net::SocketAddr = "127.0.0.1:8001".parse().unwrap();
+        let ring = Ring::new(&[(a, 3_000_000), (b, 1_000_000)]);
+        assert_eq!(ring.points.len(), POINTS * 2);
+        let count = ring.points.iter().filter(|x| x.1 == a).count();
+        assert_eq!(count, POINTS * 2 * 3 / 4);
+    }
+}
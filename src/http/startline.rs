@@ -1,8 +1,10 @@
 use std::{borrow::Cow, str, str::FromStr};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum HttpVersion {
     HTTP0,
+    // kept apart from 1.1, so a rewritten start line goes upstream as the client sent it
+    HTTP10,
     HTTP1, // HTTP pipeline not supported
     HTTP2,
     HTTP3,
@@ -16,7 +18,7 @@ impl TryFrom<&[u8]> for HttpVersion {
         match input {
             b"HTTP" => Ok(HttpVersion::Unknown),
             b"HTTP/0.9" => Ok(HttpVersion::HTTP0),
-            b"HTTP/1.0" => Ok(HttpVersion::HTTP1),
+            b"HTTP/1.0" => Ok(HttpVersion::HTTP10),
             b"HTTP/1.1" => Ok(HttpVersion::HTTP1),
             b"HTTP/2" => Ok(HttpVersion::HTTP2),
             b"HTTP/3" => Ok(HttpVersion::HTTP3),
@@ -31,7 +33,7 @@ impl FromStr for HttpVersion {
         match input {
             "HTTP" => Ok(HttpVersion::Unknown),
             "HTTP/0.9" => Ok(HttpVersion::HTTP0),
-            "HTTP/1.0" => Ok(HttpVersion::HTTP1),
+            "HTTP/1.0" => Ok(HttpVersion::HTTP10),
             "HTTP/1.1" => Ok(HttpVersion::HTTP1),
             "HTTP/2" => Ok(HttpVersion::HTTP2),
             "HTTP/3" => Ok(HttpVersion::HTTP3),
@@ -40,7 +42,7 @@ impl FromStr for HttpVersion {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Method {
     GET,
     CONNECT,
@@ -51,6 +53,8 @@ pub enum Method {
     OPTIONS,
     TRACE,
     PATCH,
+    // cache invalidation
+    PURGE,
 }
 
 impl TryFrom<&[u8]> for Method {
@@ -67,6 +71,7 @@ impl TryFrom<&[u8]> for Method {
             b"OPTIONS" => Ok(Method::OPTIONS),
             b"TRACE" => Ok(Method::TRACE),
             b"PATCH" => Ok(Method::PATCH),
+            b"PURGE" => Ok(Method::PURGE),
             _ => Err(Error::MisMatchedValue),
         }
     }
@@ -85,18 +90,84 @@ impl FromStr for Method {
             "OPTIONS" => Ok(Method::OPTIONS),
             "TRACE" => Ok(Method::TRACE),
             "PATCH" => Ok(Method::PATCH),
+            "PURGE" => Ok(Method::PURGE),
             _ => Err(()),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+impl HttpVersion {
+    pub fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            HttpVersion::HTTP0 => b"HTTP/0.9",
+            HttpVersion::HTTP10 => b"HTTP/1.0",
+            HttpVersion::HTTP1 => b"HTTP/1.1",
+            HttpVersion::HTTP2 => b"HTTP/2",
+            HttpVersion::HTTP3 => b"HTTP/3",
+            HttpVersion::Unknown => b"HTTP",
+        }
+    }
+}
+
+impl Method {
+    /// Whether sending the request twice has the same effect as once
+    pub fn idempotent(&self) -> bool {
+        !matches!(self, Method::POST | Method::PATCH | Method::CONNECT)
+    }
+    pub fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            Method::GET => b"GET",
+            Method::POST => b"POST",
+            Method::HEAD => b"HEAD",
+            Method::PUT => b"PUT",
+            Method::DELETE => b"DELETE",
+            Method::CONNECT => b"CONNECT",
+            Method::OPTIONS => b"OPTIONS",
+            Method::TRACE => b"TRACE",
+            Method::PATCH => b"PATCH",
+            Method::PURGE => b"PURGE",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct StartLine {
     pub method: Method,
     pub version: HttpVersion,
     pub path: Vec<u8>,
 }
 
+impl StartLine {
+    /// Split the request target into `scheme://authority` and `path?query`
+    ///
+    /// The first part is empty for origin-form targets(`/index.html`)
+    pub fn split_target(&self) -> (&[u8], &[u8]) {
+        let path = self.path.as_slice();
+        let scheme_end = match path.windows(3).position(|x| x == b"://") {
+            Some(x) if !path.starts_with(b"/") => x + 3,
+            _ => return (&path[0..0], path),
+        };
+        match path[scheme_end..]
+            .iter()
+            .position(|&x| x == b'/' || x == b'?')
+        {
+            Some(x) => path.split_at(scheme_end + x),
+            None => (path, &path[path.len()..]),
+        }
+    }
+    /// Serialize the start line without the trailing CRLF
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [
+            self.method.as_bytes(),
+            b" ",
+            &self.path,
+            b" ",
+            self.version.as_bytes(),
+        ]
+        .concat()
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     BadFormat,
@@ -154,4 +225,33 @@ mod test {
 
         assert_eq!(expect_result, result);
     }
+
+    #[test]
+    fn split_target() {
+        let source = b"GET http://a.example.com/index.html?a=1 HTTP/1.1";
+        let result: StartLine = source.as_ref().try_into().unwrap();
+        let (authority, origin) = result.split_target();
+        assert_eq!(authority, b"http://a.example.com");
+        assert_eq!(origin, b"/index.html?a=1");
+
+        let source = b"GET /index.html HTTP/1.1";
+        let result: StartLine = source.as_ref().try_into().unwrap();
+        assert_eq!(result.split_target(), (&b""[..], &b"/index.html"[..]));
+        assert_eq!(result.to_bytes(), source.to_vec());
+    }
+
+    #[test]
+    fn http10_round_trip() {
+        let source = b"GET /index.html HTTP/1.0";
+        let mut result: StartLine = source.as_ref().try_into().unwrap();
+        assert_eq!(result.version, HttpVersion::HTTP10);
+        assert_eq!(result.to_bytes(), source.to_vec());
+
+        // rewritten target, same version
+        result.path = b"/backend/index.html".to_vec();
+        assert_eq!(
+            result.to_bytes(),
+            b"GET /backend/index.html HTTP/1.0".to_vec()
+        );
+    }
 }
@@ -0,0 +1,171 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt::Write;
+use std::fs;
+use std::io::{self, Read};
+use std::net;
+use std::time::Duration;
+
+use super::header::Fields;
+
+/// Session affinity by cookie
+///
+/// The first response sets `cookie` naming the upstream picked, signed with `secret`,
+/// so later requests carrying it go to the same upstream while it is up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sticky {
+    pub cookie: String,
+    pub secret: Vec<u8>,
+    // session cookie when None
+    pub max_age: Option<Duration>,
+}
+
+impl Sticky {
+    /// Default settings with a secret of its own for each process, so cookies do not outlive it
+    pub fn new() -> io::Result<Self> {
+        let mut secret = vec![0_u8; 32];
+        fs::File::open("/dev/urandom")?.read_exact(&mut secret)?;
+        Ok(Sticky {
+            cookie: "upstream".to_string(),
+            secret,
+            max_age: None,
+        })
+    }
+}
+
+/// Upstreams of a host with the cookie value naming each
+///
+/// The value is the signature of the address, which tells nothing of the address
+/// to client and can not be made up without the secret.
+#[derive(Debug)]
+pub struct Affinity {
+    sticky: Sticky,
+    tokens: Vec<(net::SocketAddr, String)>,
+}
+
+impl Affinity {
+    pub fn new(sticky: Sticky, addrs: Vec<net::SocketAddr>) -> Self {
+        let tokens = addrs
+            .into_iter()
+            .map(|x| {
+                let signature = sign(&sticky.secret, x).finalize().into_bytes();
+                let token = signature.iter().fold(String::new(), |mut token, x| {
+                    write!(token, "{:02x}", x).unwrap();
+                    token
+                });
+                (x, token)
+            })
+            .collect();
+        Affinity { sticky, tokens }
+    }
+
+    /// Upstream named by the cookie of the request, None without a valid one
+    pub fn upstream(&self, fields: &Fields) -> Option<net::SocketAddr> {
+        let signature = unhex(fields.cookie(&self.sticky.cookie)?)?;
+        self.tokens.iter().map(|(x, _)| *x).find(|x| {
+            sign(&self.sticky.secret, *x)
+                .verify_slice(&signature)
+                .is_ok()
+        })
+    }
+
+    /// `Set-Cookie` line naming `addr`, None when the request already carries it
+    pub fn set_cookie(&self, addr: net::SocketAddr, fields: &Fields) -> Option<Vec<u8>> {
+        if self.upstream(fields) == Some(addr) {
+            return None;
+        }
+        let (_, token) = self.tokens.iter().find(|(x, _)| *x == addr)?;
+        let mut line = format!(
+            "Set-Cookie: {}={}; Path=/; HttpOnly",
+            self.sticky.cookie, token
+        );
+        if let Some(x) = self.sticky.max_age {
+            write!(line, "; Max-Age={}", x.as_secs()).unwrap();
+        }
+        if fields.is_secure() {
+            line.push_str("; Secure");
+        }
+        Some(line.into_bytes())
+    }
+}
+
+// HMAC-SHA256 of the address, to be finalized or verified
+fn sign(secret: &[u8], addr: net::SocketAddr) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(addr.to_string().as_bytes());
+    mac
+}
+
+// bytes of lowercase hex, None when it is not
+fn unhex(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|x| {
+            let x = std::str::from_utf8(x).ok()?;
+            u8::from_str_radix(x, 16).ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cookie() {
+        let addrs: Vec<net::SocketAddr> = ["127.0.0.1:8000", "127.0.0.1:8001"]
+            .iter()
+            .map(|x| x.parse().unwrap())
+            .collect();
+        let sticky = Sticky {
+            cookie: "backend".to_string(),
+            secret: b"secret".to_vec(),
+            max_age: Some(Duration::from_secs(3600)),
+        };
+        let affinity = Affinity::new(sticky, addrs.clone());
+
+        let line = affinity.set_cookie(addrs[1], &Fields::new()).unwrap();
+        let line = String::from_utf8(line).unwrap();
+        assert!(line.starts_with("Set-Cookie: backend="));
+        assert!(line.ends_with("; Path=/; HttpOnly; Max-Age=3600"));
+        assert!(!line.contains("8001"));
+
+        let cookie = line
+            .split(';')
+            .next()
+            .unwrap()
+            .replace("Set-Cookie", "Cookie");
+        let mut fields = Fields::new();
+        fields.push(cookie.as_bytes());
+        assert_eq!(affinity.upstream(&fields), Some(addrs[1]));
+        assert!(affinity.set_cookie(addrs[1], &fields).is_none());
+        // moved to another upstream, the cookie follows
+        assert!(affinity.set_cookie(addrs[0], &fields).is_some());
+
+        // made up or signed with another secret
+        let mut fields = Fields::new();
+        fields.push(b"Cookie: backend=0123456789abcdef0123456789abcdef");
+        assert_eq!(affinity.upstream(&fields), None);
+        let other = Sticky {
+            secret: b"other".to_vec(),
+            ..affinity.sticky.clone()
+        };
+        let other = Affinity::new(other, addrs.clone());
+        let line = other.set_cookie(addrs[1], &Fields::new()).unwrap();
+        let line = String::from_utf8(line).unwrap();
+        let mut fields = Fields::new();
+        fields.push(
+            line.split(';')
+                .next()
+                .unwrap()
+                .replace("Set-Cookie", "Cookie")
+                .as_bytes(),
+        );
+        assert_eq!(affinity.upstream(&fields), None);
+        let mut fields = Fields::new();
+        fields.push(b"Cookie: backend=not-hex");
+        assert_eq!(affinity.upstream(&fields), None);
+    }
+}
@@ -0,0 +1,306 @@
+use futures::AsyncReadExt;
+use std::io::{self, BufRead};
+use std::{marker, str};
+
+use super::header::Fields;
+use super::startline::Method;
+use crate::poll::network::ReadWrapper;
+
+const CHUNK_SIZE: usize = 16384;
+const MAX_HEAD_SIZE: usize = 65536;
+
+fn invalid() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "malformed response from upstream",
+    )
+}
+
+/// Status line and header fields of a response from upstream
+#[derive(Debug, Clone)]
+pub struct Head {
+    pub version: Vec<u8>,
+    pub status: u16,
+    pub reason: Vec<u8>,
+    pub fields: Fields,
+}
+
+/// How the end of response body is determined
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    Empty,
+    Length(u64),
+    Chunked,
+    Close,
+}
+
+impl Head {
+    /// Parse the response head, the empty line ending it is optional
+    pub fn parse(input: &[u8]) -> Option<Head> {
+        let mut lines = input
+            .split(|&x| x == b'\n')
+            .map(|x| x.strip_suffix(b"\r").unwrap_or(x));
+
+        let mut status_line = lines.next()?.splitn(3, |&x| x == b' ');
+        let version = status_line.next()?;
+        if !version.starts_with(b"HTTP/") {
+            return None;
+        }
+        let status = str::from_utf8(status_line.next()?).ok()?.parse().ok()?;
+        let reason = status_line.next().unwrap_or_default();
+
+        let mut fields = Fields::new();
+        for line in lines.take_while(|x| !x.is_empty()) {
+            fields.push(line);
+        }
+        Some(Head {
+            version: version.to_vec(),
+            status,
+            reason: reason.to_vec(),
+            fields,
+        })
+    }
+
+    /// Status line without CRLF
+    pub fn status_line(&self) -> Vec<u8> {
+        let mut output = self.version.clone();
+        output.extend_from_slice(format!(" {} ", self.status).as_bytes());
+        output.extend_from_slice(&self.reason);
+        output
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut output = self.status_line();
+        output.extend_from_slice(b"\r\n");
+        output.extend_from_slice(&self.fields.to_bytes());
+        output.extend_from_slice(b"\r\n");
+        output
+    }
+
+    /// Whether upstream keeps the connection open after the response
+    pub fn keep_alive(&self) -> bool {
+        let mut tokens = self
+            .fields
+            .get_all("connection")
+            .flat_map(|x| x.split(|&x| x == b','))
+            .map(|x| x.trim_ascii());
+        if self.version == b"HTTP/1.0" {
+            tokens.any(|x| x.eq_ignore_ascii_case(b"keep-alive"))
+        } else {
+            !tokens.any(|x| x.eq_ignore_ascii_case(b"close"))
+        }
+    }
+
+    /// Framing of the body, which depends on the request method as well
+    pub fn framing(&self, method: &Method) -> Framing {
+        if *method == Method::HEAD
+            || (100..200).contains(&self.status)
+            || self.status == 204
+            || self.status == 304
+        {
+            return Framing::Empty;
+        }
+        let chunked = self.fields.get_all("transfer-encoding").any(|x| {
+            x.split(|&x| x == b',')
+                .next_back()
+                .map(|x| x.trim_ascii().eq_ignore_ascii_case(b"chunked"))
+                .unwrap_or(false)
+        });
+        if chunked {
+            return Framing::Chunked;
+        }
+        match self.fields.get("content-length") {
+            Some(x) => match str::from_utf8(x).ok().and_then(|x| x.parse().ok()) {
+                Some(x) => Framing::Length(x),
+                None => Framing::Close,
+            },
+            None => Framing::Close,
+        }
+    }
+}
+
+/// Read the response head, returning bytes read past it
+pub async fn read_head<R>(reader: R) -> Result<(Head, Vec<u8>), io::Error>
+where
+    R: io::Read + marker::Unpin,
+{
+    let mut reader = ReadWrapper::new(reader);
+    let mut buffer = vec![];
+    let mut chunk = [0_u8; CHUNK_SIZE];
+    loop {
+        let byte_read = reader.read(&mut chunk).await?;
+        if byte_read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        // the terminator may span two reads
+        let searched = buffer.len().saturating_sub(3);
+        buffer.extend_from_slice(&chunk[..byte_read]);
+        if let Some(x) = buffer[searched..].windows(4).position(|x| x == b"\r\n\r\n") {
+            let end = searched + x + 4;
+            let head = Head::parse(&buffer[..end]).ok_or_else(invalid)?;
+            return Ok((head, buffer[end..].to_vec()));
+        }
+        if buffer.len() > MAX_HEAD_SIZE {
+            return Err(invalid());
+        }
+    }
+}
+
+/// Payload of a response body, with chunked framing removed
+pub struct Body<R>
+where
+    R: BufRead,
+{
+    reader: R,
+    framing: Framing,
+    // bytes left in the current chunk
+    remaining: u64,
+    done: bool,
+}
+
+impl<R> Body<R>
+where
+    R: BufRead,
+{
+    pub fn new(reader: R, framing: Framing) -> Self {
+        let (remaining, done) = match framing {
+            Framing::Length(x) => (x, x == 0),
+            Framing::Empty => (0, true),
+            _ => (0, false),
+        };
+        Body {
+            reader,
+            framing,
+            remaining,
+            done,
+        }
+    }
+
+    /// The reader, when the body was read to the end
+    pub fn finish(self) -> Option<R> {
+        self.done.then_some(self.reader)
+    }
+
+    fn line(&mut self) -> Result<String, io::Error> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(line)
+    }
+
+    // read the size line of the next chunk, skipping trailers after the last one
+    fn next_chunk(&mut self) -> Result<(), io::Error> {
+        let line = self.line()?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        self.remaining = u64::from_str_radix(size, 16).map_err(|_| invalid())?;
+        if self.remaining == 0 {
+            while !self.line()?.trim_end().is_empty() {}
+            self.done = true;
+        }
+        Ok(())
+    }
+}
+
+impl<R> io::Read for Body<R>
+where
+    R: BufRead,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        match self.framing {
+            Framing::Empty => Ok(0),
+            Framing::Close => self.reader.read(buf),
+            Framing::Length(_) => {
+                let limit = buf.len().min(self.remaining as usize);
+                let byte_read = self.reader.read(&mut buf[..limit])?;
+                if byte_read == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                self.remaining -= byte_read as u64;
+                self.done = self.remaining == 0;
+                Ok(byte_read)
+            }
+            Framing::Chunked => {
+                if self.remaining == 0 {
+                    self.next_chunk()?;
+                    if self.done {
+                        return Ok(0);
+                    }
+                }
+                let limit = buf.len().min(self.remaining as usize);
+                let byte_read = self.reader.read(&mut buf[..limit])?;
+                if byte_read == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                self.remaining -= byte_read as u64;
+                if self.remaining == 0 {
+                    // CRLF after chunk data
+                    self.line()?;
+                }
+                Ok(byte_read)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn response_head() {
+        let head = Head::parse(
+            b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 12\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(head.status, 200);
+        assert_eq!(
+            head.fields.get("content-type"),
+            Some(&b"application/json"[..])
+        );
+        assert_eq!(head.framing(&Method::GET), Framing::Length(12));
+        assert_eq!(head.framing(&Method::HEAD), Framing::Empty);
+        assert_eq!(
+            head.to_bytes(),
+            b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 12\r\n\r\n"
+        );
+
+        let head = Head::parse(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\r\n");
+        assert_eq!(head.unwrap().framing(&Method::GET), Framing::Chunked);
+        assert!(Head::parse(b"SSH-2.0-OpenSSH\r\n\r\n").is_none());
+
+        let head = Head::parse(b"HTTP/1.1 200 OK\r\nConnection: Upgrade, close\r\n\r\n");
+        assert!(!head.unwrap().keep_alive());
+        let head = Head::parse(b"HTTP/1.0 200 OK\r\nConnection: keep-alive\r\n\r\n");
+        assert!(head.unwrap().keep_alive());
+        assert!(!Head::parse(b"HTTP/1.0 200 OK\r\n\r\n")
+            .unwrap()
+            .keep_alive());
+    }
+
+    #[object::test]
+    async fn head_reading() {
+        let input = b"HTTP/1.1 204 No Content\r\nServer: a\r\n\r\nrest".as_ref();
+        let (head, rest) = read_head(input).await.unwrap();
+        assert_eq!(head.status, 204);
+        assert_eq!(rest, b"rest");
+    }
+
+    #[test]
+    fn chunked_body() {
+        let input = b"5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: x\r\n\r\nnext".as_ref();
+        let mut body = Body::new(input, Framing::Chunked);
+        let mut output = vec![];
+        body.read_to_end(&mut output).unwrap();
+        assert_eq!(output, b"hello world");
+
+        let mut body = Body::new(b"hello world".as_ref(), Framing::Length(5));
+        let mut output = vec![];
+        body.read_to_end(&mut output).unwrap();
+        assert_eq!(output, b"hello");
+    }
+}
@@ -9,6 +9,7 @@ use http::prelude::*;
 use pool::*;
 use std::net;
 use std::sync::Arc;
+use std::thread;
 
 fn main() {
     let config = Arc::new(AppState::new("config.yml"));
@@ -25,6 +26,22 @@ fn main() {
         thread, addr
     );
 
+    if let Some(admin) = config.admin.clone() {
+        let listener = net::TcpListener::bind(admin.clone()).unwrap();
+        let state = config.clone();
+        println!("admin endpoint on address {:?}", admin);
+        // requests to admin are rare, answered one at a time
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                futures::executor::block_on(http::admin::handle(&state, &stream)).ok();
+            }
+        });
+    }
+
+    for health in config.health_checks() {
+        health.spawn();
+    }
+
     for stream in listener.incoming() {
         let stream = stream.unwrap();
         pool.execute(handle_request((config.clone(), stream)));
@@ -36,6 +53,8 @@ fn future_handler(future: impl futures::Future<Output = ()>) {
 }
 
 async fn handle_request(config: (Arc<AppState>, net::TcpStream)) {
+    let (state, client_stream) = config;
+
     macro_rules! log_err {
         ($i:expr) => {
             match $i {
@@ -45,6 +64,14 @@ async fn handle_request(config: (Arc<AppState>, net::TcpStream)) {
                         Error::ClientIncompatible => println!("Bad request from downstream"),
                         Error::ServerIncompatible => println!("Bad request from upstream"),
                         Error::BadProtocal => println!("Protocal not supported"),
+                        Error::BadHost => {
+                            println!("Malformed host from downstream");
+                            Response::new(400).send(&client_stream).await.ok();
+                        }
+                        Error::TooLarge => {
+                            println!("Request body too large from downstream");
+                            Response::new(413).send(&client_stream).await.ok();
+                        }
                     }
                     return;
                 }
@@ -52,12 +79,94 @@ async fn handle_request(config: (Arc<AppState>, net::TcpStream)) {
         };
     }
 
-    let (state, client_stream) = config;
     let request = log_err!(Request::new(&client_stream));
 
-    let request = log_err!(request.parse().await);
+    let mut request = log_err!(request.parse().await);
+    if let Ok(x) = client_stream.peer_addr() {
+        request.set_peer(x.ip());
+    }
+    request.distrust_forwarded(state.as_ref());
+
+    let (route, captures) = log_err!(request.route(state.as_ref()));
+    let headers = route.response_headers(request.is_secure());
 
-    let server_stream = log_err!(request.send(state.as_ref()).await);
+    let proxy = match &route.action {
+        Action::Proxy(x) => x,
+        Action::Redirect(x) => {
+            let mut response = x.response(request.startline(), &captures);
+            response.headers.extend(headers);
+            response.send(&client_stream).await.ok();
+            return;
+        }
+        Action::Return(x) => {
+            let mut response = x.clone();
+            response.headers.extend(headers);
+            response.send(&client_stream).await.ok();
+            return;
+        }
+        Action::Files(x) => {
+            x.serve(
+                request.startline(),
+                request.fields(),
+                headers,
+                &client_stream,
+            )
+            .await
+            .ok();
+            return;
+        }
+    };
+
+    if let Some(cache) = route.cache() {
+        let host = log_err!(request.host(state.as_ref()));
+        if request.startline().method == startline::Method::PURGE {
+            let allowed = client_stream
+                .peer_addr()
+                .map(|x| route.purge_allowed(x.ip()))
+                .unwrap_or(false);
+            let response = if allowed {
+                let count: usize = Purge::request(&host, request.startline(), request.fields())
+                    .iter()
+                    .map(|x| cache.purge(x))
+                    .sum();
+                Response::new(200).body(format!("purged {}\n", count).into_bytes())
+            } else {
+                Response::new(403)
+            };
+            response.send(&client_stream).await.ok();
+            return;
+        }
+        if let Some(key) = Cache::key(&host, request.startline(), request.fields()) {
+            log_err!(
+                cached_proxy(
+                    request,
+                    proxy,
+                    &captures,
+                    cache,
+                    &key,
+                    &client_stream,
+                    &headers
+                )
+                .await
+            );
+            return;
+        }
+    }
 
-    log_err!(reverse_proxy(server_stream, client_stream).await);
+    let method = request.startline().method.clone();
+    let compression = proxy.compression(request.startline(), request.fields());
+
+    let upstream = log_err!(request.send(proxy, &captures, &client_stream).await);
+
+    log_err!(
+        reverse_proxy(
+            upstream,
+            &client_stream,
+            &headers,
+            &method,
+            compression,
+            proxy.buffer_response()
+        )
+        .await
+    );
 }